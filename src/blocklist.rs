@@ -0,0 +1,83 @@
+use std::collections::HashSet;
+use std::fs;
+
+use bitcoin::hashes::hex::FromHex;
+use bitcoin::secp256k1::PublicKey;
+use lightning::routing::gossip::NodeId;
+
+/// Nodes and channels that are excluded from all snapshots.
+///
+/// Blocked channels, as well as all channels of blocked nodes, are simply absent from the output,
+/// exactly as if the server had never seen their gossip, and are not reflected in any counts.
+pub(crate) struct Blocklist {
+	pub(crate) node_ids: HashSet<NodeId>,
+	pub(crate) short_channel_ids: HashSet<u64>,
+}
+
+impl Blocklist {
+	/// Read a blocklist file, which contains one entry per line: either a hex-encoded node pubkey,
+	/// or a short channel ID, given either as an integer or in the `blockxtxxoutput` format.
+	/// Empty lines and lines starting with `#` are ignored.
+	pub(crate) fn load(path: &str) -> Result<Self, String> {
+		let contents = fs::read_to_string(path).map_err(|error| format!("Failed to read blocklist file {}: {}", path, error))?;
+		Self::parse(&contents).map_err(|error| format!("Invalid blocklist file {}: {}", path, error))
+	}
+
+	fn parse(contents: &str) -> Result<Self, String> {
+		let mut node_ids = HashSet::new();
+		let mut short_channel_ids = HashSet::new();
+		for line in contents.lines().map(str::trim) {
+			if line.is_empty() || line.starts_with('#') {
+				continue;
+			}
+			if line.len() == 66 {
+				let pubkey = Vec::from_hex(line).map_err(|_| format!("Invalid node pubkey: {}", line))?;
+				let pubkey = PublicKey::from_slice(&pubkey).map_err(|_| format!("Invalid node pubkey: {}", line))?;
+				node_ids.insert(NodeId::from_pubkey(&pubkey));
+			} else {
+				short_channel_ids.insert(parse_short_channel_id(line).ok_or(format!("Invalid short channel ID: {}", line))?);
+			}
+		}
+		Ok(Self { node_ids, short_channel_ids })
+	}
+}
+
+fn parse_short_channel_id(scid: &str) -> Option<u64> {
+	let components: Vec<&str> = scid.split('x').collect();
+	match components[..] {
+		[scid] => scid.parse::<u64>().ok(),
+		[block, transaction, output] => {
+			let block = block.parse::<u32>().ok().filter(|block| *block < (1 << 24))?;
+			let transaction = transaction.parse::<u32>().ok().filter(|transaction| *transaction < (1 << 24))?;
+			let output = output.parse::<u16>().ok()?;
+			Some(((block as u64) << 40) | ((transaction as u64) << 16) | output as u64)
+		}
+		_ => None,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::Blocklist;
+
+	#[test]
+	fn test_blocklist_parsing() {
+		let contents = "
+			# a node
+			035e4ff418fc8b5554c5d9eea66396c227bd429a3251c8cbc711002ba215bfc226
+
+			# two channels
+			770000x1234x1
+			846641372938141697
+		";
+		let blocklist = Blocklist::parse(contents).unwrap();
+		assert_eq!(blocklist.node_ids.len(), 1);
+		assert!(blocklist.short_channel_ids.contains(&((770000 << 40) | (1234 << 16) | 1)));
+		assert!(blocklist.short_channel_ids.contains(&846641372938141697));
+
+		assert!(Blocklist::parse("770000x1234").is_err());
+		assert!(Blocklist::parse("16777216x0x0").is_err());
+		assert!(Blocklist::parse("not a channel").is_err());
+		assert!(Blocklist::load("/nonexistent/blocklist.txt").is_err());
+	}
+}
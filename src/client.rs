@@ -1,12 +1,137 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use lightning::util::ser::Writeable;
 use ureq::Agent;
+use crate::hashing::HashAlgorithm;
 use crate::{SerializedResponse, config};
+use crate::config::ConfigError;
+
+/// How a [`SerializedResponse`] is encoded in the upload request body
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UploadEncoding {
+    /// JSON, for interoperability with existing ingest servers
+    Json,
+    /// The compact length-prefixed encoding of [`SerializedResponse`]'s `Writeable` implementation
+    Binary,
+    /// The snapshot data as is, with the counts sent in `X-RGS-*-Count` headers. This is the only
+    /// encoding that allows uploading the compressed snapshot files unchanged.
+    Raw,
+}
+
+/// What the ingest server reported back for a successful upload
+#[derive(Debug, Clone)]
+pub struct UploadOutcome {
+    pub status: u16,
+    /// The ID the server stored the snapshot under, if its response body included one
+    pub id: Option<String>,
+    /// The canonical URL of the stored snapshot, if its response body included one
+    pub url: Option<String>,
+    /// The size of the request body
+    pub bytes_sent: usize,
+}
+
+impl UploadOutcome {
+    /// Pick the `id` and `url` fields out of a JSON response body, tolerating any other (or no)
+    /// body, as not all ingest servers send one.
+    fn from_response(status: u16, body: &str, bytes_sent: usize) -> Self {
+        let body: Option<serde_json::Value> = serde_json::from_str(body).ok();
+        let field = |name: &str| {
+            body.as_ref().and_then(|body| body.get(name)).and_then(|value| match value {
+                serde_json::Value::String(value) => Some(value.clone()),
+                serde_json::Value::Number(value) => Some(value.to_string()),
+                _ => None,
+            })
+        };
+        UploadOutcome { status, id: field("id"), url: field("url"), bytes_sent }
+    }
+}
+
+/// The size of a snapshot without any nodes, announcements, or updates: the prefix, the chain
+/// hash, the timestamp, and the three counts
+const MIN_SNAPSHOT_LENGTH: usize = 4 + 32 + 4 + 3 * 4;
+
+/// Check that a snapshot looks complete before it's uploaded, so that a local bug can't overwrite
+/// a good snapshot on the server with a truncated one.
+pub(crate) fn validate_snapshot(snapshot: &SerializedResponse) -> anyhow::Result<()> {
+    if snapshot.data.len() < MIN_SNAPSHOT_LENGTH {
+        return Err(anyhow::anyhow!("Refusing to upload snapshot of only {} bytes", snapshot.data.len()));
+    }
+    if snapshot.data[..4] != crate::GOSSIP_PREFIX {
+        return Err(anyhow::anyhow!("Refusing to upload snapshot with an invalid prefix"));
+    }
+    if snapshot.message_count != snapshot.announcement_count + snapshot.update_count
+        || snapshot.update_count != snapshot.update_count_full + snapshot.update_count_incremental {
+        return Err(anyhow::anyhow!("Refusing to upload snapshot with inconsistent counts"));
+    }
+    // every announcement takes at least five bytes and every update at least two, plus the
+    // default update values
+    let mut min_length = MIN_SNAPSHOT_LENGTH + 5 * snapshot.announcement_count as usize + 2 * snapshot.update_count as usize;
+    if snapshot.update_count > 0 {
+        min_length += 2 + 8 + 4 + 4 + 8;
+    }
+    if snapshot.data.len() < min_length {
+        return Err(anyhow::anyhow!("Refusing to upload snapshot of {} bytes, which is too short for its counts", snapshot.data.len()));
+    }
+    Ok(())
+}
+
+/// Uploads snapshots to an ingest server. This is implemented by [`Client`], and exists so that
+/// the snapshotter can be handed a different implementation, e.g. one recording the uploads.
+pub trait Uploader: Send + Sync {
+    /// Like [`Self::post_snapshot_ref`], but taking ownership of the snapshot
+    fn post_snapshot(&self, snapshot: SerializedResponse, timestamp: u64, token: String) -> anyhow::Result<UploadOutcome> {
+        self.post_snapshot_ref(&snapshot, timestamp, token)
+    }
+    /// See [`Client::post_snapshot_ref`]
+    fn post_snapshot_ref(&self, snapshot: &SerializedResponse, timestamp: u64, token: String) -> anyhow::Result<UploadOutcome>;
+    /// See [`Client::post_compressed_snapshot`]
+    fn post_compressed_snapshot(&self, snapshot: &SerializedResponse, compressed_data: Vec<u8>, content_encoding: &str, timestamp: u64, token: String) -> anyhow::Result<UploadOutcome>;
+    /// See [`Client::post_snapshot_file`]
+    fn post_snapshot_file(&self, path: &str, snapshot: SerializedResponse, content_encoding: &str, timestamp: u64, token: String) -> anyhow::Result<UploadOutcome>;
+}
+
+/// An uploader and the API key to upload with
+pub(crate) type UploadTarget = (Arc<dyn Uploader>, String);
+
+/// Where the snapshots are uploaded through, and with which API key
+#[derive(Clone)]
+pub(crate) enum UploadCredentials {
+    /// A [`Client`] for the configured URL, with the configured API key. The key is read for
+    /// every upload, so that a rotated key file is picked up, and nothing is uploaded without one.
+    Configured,
+    /// The given uploader and API key, which also enables uploads if no API key is configured
+    Fixed(Arc<dyn Uploader>, String),
+}
+
+impl UploadCredentials {
+    /// The uploader and API key to upload with, if any
+    pub(crate) fn resolve(&self) -> Result<Option<UploadTarget>, ConfigError> {
+        match self {
+            UploadCredentials::Configured => Ok(config::try_upload_api_key()?.map(|api_key| (Arc::new(Client::new()) as Arc<dyn Uploader>, api_key))),
+            UploadCredentials::Fixed(uploader, api_key) => Ok(Some((Arc::clone(uploader), api_key.clone()))),
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Client {
     pub base_url: String,
+    pub encoding: UploadEncoding,
+    pub user_agent: String,
+    /// Prepended to the unique `X-Request-Id` sent with every request
+    pub request_id_prefix: String,
+    /// The algorithm the uploaded snapshots' content hashes were computed with
+    pub hash_algorithm: HashAlgorithm,
     agent: Agent,
 }
 
+/// Distinguishes requests generated within the same nanosecond
+static REQUEST_COUNTER: AtomicU64 = AtomicU64::new(0);
+
 impl Client {
     /// build a blocking client from a [`Builder`]
     pub fn new() -> Self {
@@ -17,23 +142,128 @@ impl Client {
 
     /// build a blocking client from an [`Agent`]
     pub fn from_agent(base_url: String, agent: Agent) -> Self {
-        Client { base_url, agent }
+        Client {
+            base_url,
+            encoding: config::upload_encoding(),
+            user_agent: config::upload_user_agent(),
+            request_id_prefix: config::upload_request_id_prefix(),
+            hash_algorithm: config::hash_algorithm(),
+            agent,
+        }
+    }
+
+    fn next_request_id(&self) -> String {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+        let counter = REQUEST_COUNTER.fetch_add(1, Ordering::Relaxed);
+        format!("{}-{:x}-{:x}-{}", self.request_id_prefix, nanos, std::process::id(), counter)
     }
 
-    pub fn post_snapshot(
+    /// Upload a borrowed snapshot, so that the caller can keep using its data afterwards. The raw
+    /// upload encoding sends the data without copying it.
+    pub fn post_snapshot_ref(
         &self,
+        snapshot: &SerializedResponse,
+        timestamp: u64,
+        token: String,
+    ) -> anyhow::Result<UploadOutcome> {
+        validate_snapshot(snapshot)?;
+        let request = self.set_content_hash_headers(self.request(timestamp, &token), snapshot);
+        match self.encoding {
+            UploadEncoding::Json => Self::send(request.set("Content-Type", "application/json"), &serde_json::to_vec(snapshot)?),
+            UploadEncoding::Binary => Self::send(request.set("Content-Type", "application/octet-stream"), &snapshot.encode()),
+            UploadEncoding::Raw => Self::send(Self::set_count_headers(request, snapshot).set("Content-Type", "application/octet-stream"), &snapshot.data),
+        }
+    }
+
+    /// Upload the already compressed data of a snapshot unchanged, labeled with the matching
+    /// `Content-Encoding`, so that the uploaded bytes are identical to the compressed snapshot
+    /// file. This requires the raw upload encoding, because the others wrap the data.
+    pub fn post_compressed_snapshot(
+        &self,
+        snapshot: &SerializedResponse,
+        compressed_data: Vec<u8>,
+        content_encoding: &str,
+        timestamp: u64,
+        token: String,
+    ) -> anyhow::Result<UploadOutcome> {
+        if self.encoding != UploadEncoding::Raw {
+            return Err(anyhow::anyhow!("Compressed uploads require the raw upload encoding"));
+        }
+        validate_snapshot(snapshot)?;
+        let request = Self::set_count_headers(self.set_content_hash_headers(self.request(timestamp, &token), snapshot), snapshot)
+            .set("Content-Type", "application/octet-stream")
+            .set("Content-Encoding", content_encoding);
+        Self::send(request, &compressed_data)
+    }
+
+    /// Upload a snapshot by streaming the written file at `path`, which holds its data encoded
+    /// with `content_encoding`, rather than from memory, so that the snapshot doesn't have to be
+    /// kept resident for the duration of the upload. The snapshot is validated before its data
+    /// is dropped. Like [`Self::post_compressed_snapshot`], this requires the raw upload encoding.
+    pub fn post_snapshot_file(
+        &self,
+        path: &str,
         snapshot: SerializedResponse,
+        content_encoding: &str,
         timestamp: u64,
         token: String,
-    ) -> anyhow::Result<()> {
-        let resp = self
+    ) -> anyhow::Result<UploadOutcome> {
+        if self.encoding != UploadEncoding::Raw {
+            return Err(anyhow::anyhow!("Streamed uploads require the raw upload encoding"));
+        }
+        validate_snapshot(&snapshot)?;
+        let snapshot = SerializedResponse { data: Vec::new(), ..snapshot };
+
+        let file = File::open(path)?;
+        let length = file.metadata()?.len();
+        let request = Self::set_count_headers(self.set_content_hash_headers(self.request(timestamp, &token), &snapshot), &snapshot)
+            .set("Content-Type", "application/octet-stream")
+            .set("Content-Encoding", content_encoding)
+            .set("Content-Length", &length.to_string());
+        Self::handle_response(request.send(BufReader::new(file)), length as usize)
+    }
+
+    fn request(&self, timestamp: u64, token: &str) -> ureq::Request {
+        self
             .agent
             .post(&format!("{}/v1/rgs/snapshot/{}", self.base_url, timestamp))
-            .set("X-API-KEY", &token)
-            .send_json(snapshot);
+            .set("X-API-KEY", token)
+            .set("User-Agent", &self.user_agent)
+            .set("X-Request-Id", &self.next_request_id())
+    }
 
-        match resp {
-            Ok(_resp) => Ok(()),
+    /// Send the snapshot's content hash along with the algorithm it was computed with. SHA-256
+    /// hashes are additionally sent in the `X-RGS-Content-Sha256` header ingest servers may check.
+    fn set_content_hash_headers(&self, request: ureq::Request, snapshot: &SerializedResponse) -> ureq::Request {
+        let request = request
+            .set("X-RGS-Content-Hash", &snapshot.content_hash)
+            .set("X-RGS-Content-Hash-Algorithm", self.hash_algorithm.name());
+        match self.hash_algorithm {
+            HashAlgorithm::Sha256 => request.set("X-RGS-Content-Sha256", &snapshot.content_hash),
+            _ => request,
+        }
+    }
+
+    fn set_count_headers(request: ureq::Request, snapshot: &SerializedResponse) -> ureq::Request {
+        request
+            .set("X-RGS-Message-Count", &snapshot.message_count.to_string())
+            .set("X-RGS-Announcement-Count", &snapshot.announcement_count.to_string())
+            .set("X-RGS-Update-Count", &snapshot.update_count.to_string())
+            .set("X-RGS-Update-Count-Full", &snapshot.update_count_full.to_string())
+            .set("X-RGS-Update-Count-Incremental", &snapshot.update_count_incremental.to_string())
+    }
+
+    fn send(request: ureq::Request, body: &[u8]) -> anyhow::Result<UploadOutcome> {
+        Self::handle_response(request.send_bytes(body), body.len())
+    }
+
+    fn handle_response(result: Result<ureq::Response, ureq::Error>, bytes_sent: usize) -> anyhow::Result<UploadOutcome> {
+        match result {
+            Ok(resp) => {
+                let status = resp.status();
+                let response_body = resp.into_string().unwrap_or_default();
+                Ok(UploadOutcome::from_response(status, &response_body, bytes_sent))
+            }
             Err(ureq::Error::Status(code, resp)) => {
                 let str = resp.into_string().ok();
                 Err(anyhow::anyhow!("{}: {}", code, str.unwrap_or_default()))
@@ -42,3 +272,17 @@ impl Client {
         }
     }
 }
+
+impl Uploader for Client {
+    fn post_snapshot_ref(&self, snapshot: &SerializedResponse, timestamp: u64, token: String) -> anyhow::Result<UploadOutcome> {
+        Client::post_snapshot_ref(self, snapshot, timestamp, token)
+    }
+
+    fn post_compressed_snapshot(&self, snapshot: &SerializedResponse, compressed_data: Vec<u8>, content_encoding: &str, timestamp: u64, token: String) -> anyhow::Result<UploadOutcome> {
+        Client::post_compressed_snapshot(self, snapshot, compressed_data, content_encoding, timestamp, token)
+    }
+
+    fn post_snapshot_file(&self, path: &str, snapshot: SerializedResponse, content_encoding: &str, timestamp: u64, token: String) -> anyhow::Result<UploadOutcome> {
+        Client::post_snapshot_file(self, path, snapshot, content_encoding, timestamp, token)
+    }
+}
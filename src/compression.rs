@@ -0,0 +1,242 @@
+use std::io::Write;
+
+use flate2::write::{GzEncoder, ZlibEncoder};
+
+/// The encoding a snapshot file variant is stored in.
+///
+/// The identity variant is always written, so that clients which can't handle compression can
+/// still request the uncompressed files. Compressed variants are written next to it, with the
+/// encoding's extension appended to both the snapshot file and the symlink names, which allows
+/// static file servers (e.g. nginx's `gzip_static`) to serve them with the right
+/// `Content-Encoding`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum SnapshotCompression {
+	Identity,
+	/// Gzip at the given compression level, from 0 (none) to 9 (best)
+	Gzip(u32),
+	/// Zlib-wrapped deflate, which is what HTTP's `deflate` encoding denotes, at the given
+	/// compression level, from 0 (none) to 9 (best)
+	Deflate(u32),
+	/// Brotli at the given quality, from 0 (fastest) to 11 (best), only available with the `brotli`
+	/// feature
+	#[cfg(feature = "brotli")]
+	Brotli(u32),
+}
+
+/// The gzip and deflate level used if the compression spec doesn't specify one
+const DEFAULT_GZIP_LEVEL: u32 = 6;
+/// The brotli quality used if the compression spec doesn't specify one
+#[cfg(feature = "brotli")]
+const DEFAULT_BROTLI_QUALITY: u32 = 9;
+/// The base-2 logarithm of the brotli window size, which is brotli's default
+#[cfg(feature = "brotli")]
+const BROTLI_WINDOW_BITS: u32 = 22;
+
+/// The compressions [`parse_compression_spec`] accepts, as shown in configuration errors
+#[cfg(not(feature = "brotli"))]
+pub(crate) const SUPPORTED_COMPRESSIONS: &str = "a comma-separated list of gzip[:level], deflate[:level], or none";
+#[cfg(feature = "brotli")]
+pub(crate) const SUPPORTED_COMPRESSIONS: &str = "a comma-separated list of gzip[:level], deflate[:level], br[:quality], or none";
+
+impl SnapshotCompression {
+	/// The suffix appended to snapshot file and symlink names for this variant
+	pub(crate) fn file_extension(&self) -> &'static str {
+		match self {
+			SnapshotCompression::Identity => "",
+			SnapshotCompression::Gzip(_) => ".gz",
+			SnapshotCompression::Deflate(_) => ".deflate",
+			#[cfg(feature = "brotli")]
+			SnapshotCompression::Brotli(_) => ".br",
+		}
+	}
+
+	/// The HTTP `Content-Encoding` matching this variant
+	pub(crate) fn content_encoding(&self) -> &'static str {
+		match self {
+			SnapshotCompression::Identity => "identity",
+			SnapshotCompression::Gzip(_) => "gzip",
+			SnapshotCompression::Deflate(_) => "deflate",
+			#[cfg(feature = "brotli")]
+			SnapshotCompression::Brotli(_) => "br",
+		}
+	}
+
+	pub(crate) fn compress(&self, data: &[u8]) -> Vec<u8> {
+		match self {
+			SnapshotCompression::Identity => data.to_vec(),
+			SnapshotCompression::Gzip(level) => {
+				let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::new(*level));
+				encoder.write_all(data).unwrap();
+				encoder.finish().unwrap()
+			}
+			SnapshotCompression::Deflate(level) => {
+				let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::new(*level));
+				encoder.write_all(data).unwrap();
+				encoder.finish().unwrap()
+			}
+			#[cfg(feature = "brotli")]
+			SnapshotCompression::Brotli(quality) => {
+				let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, *quality, BROTLI_WINDOW_BITS);
+				encoder.write_all(data).unwrap();
+				encoder.into_inner()
+			}
+		}
+	}
+}
+
+/// Parse a comma-separated list of compressed variants to write in addition to the uncompressed
+/// one, each of the form `algorithm[:level]`, e.g. `gzip:9`. `none` yields no compressed variants.
+/// Brotli, as `br` or `brotli`, requires the `brotli` feature.
+pub(crate) fn parse_compression_spec(spec: &str) -> Result<Vec<SnapshotCompression>, String> {
+	let mut variants: Vec<SnapshotCompression> = Vec::new();
+	for entry in spec.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+		let (algorithm, level) = match entry.split_once(':') {
+			Some((algorithm, level)) => {
+				let level = level.parse::<u32>().map_err(|_| format!("Invalid compression level in {}", entry))?;
+				(algorithm, Some(level))
+			}
+			None => (entry, None),
+		};
+		let variant = match algorithm.to_lowercase().as_str() {
+			"none" => {
+				if level.is_some() {
+					return Err("The none compression does not take a level".to_string());
+				}
+				continue;
+			}
+			"gzip" | "deflate" => {
+				let level = level.unwrap_or(DEFAULT_GZIP_LEVEL);
+				if level > 9 {
+					return Err(format!("{} compression level must be between 0 and 9, got {}", algorithm, level));
+				}
+				if algorithm.eq_ignore_ascii_case("gzip") { SnapshotCompression::Gzip(level) } else { SnapshotCompression::Deflate(level) }
+			}
+			#[cfg(feature = "brotli")]
+			"br" | "brotli" => {
+				let quality = level.unwrap_or(DEFAULT_BROTLI_QUALITY);
+				if quality > 11 {
+					return Err(format!("{} compression quality must be between 0 and 11, got {}", algorithm, quality));
+				}
+				SnapshotCompression::Brotli(quality)
+			}
+			#[cfg(not(feature = "brotli"))]
+			"br" | "brotli" => return Err("Brotli compression requires building with the brotli feature".to_string()),
+			_ => return Err(format!("Unsupported compression algorithm: {}", algorithm)),
+		};
+		if variants.iter().any(|existing| existing.file_extension() == variant.file_extension()) {
+			return Err(format!("Compression algorithm {} is specified more than once", algorithm));
+		}
+		variants.push(variant);
+	}
+	Ok(variants)
+}
+
+/// Pick the content encoding to serve a snapshot with from the `available` ones (e.g. `identity`
+/// and the configured compressions' encodings), according to a client's `Accept-Encoding` header.
+///
+/// Encodings are ranked by their quality values, with ties going to the one listed first in
+/// `available`. Identity is acceptable as a last resort unless explicitly excluded, as per RFC 9110.
+/// Returns `None` if none of the available encodings are acceptable.
+pub fn negotiate_content_encoding<'a>(accept_encoding: &str, available: &[&'a str]) -> Option<&'a str> {
+	let preferences: Vec<(String, f32)> = accept_encoding.split(',').map(str::trim).filter(|entry| !entry.is_empty()).map(|entry| {
+		let mut parameters = entry.split(';').map(str::trim);
+		let coding = parameters.next().unwrap_or_default().to_lowercase();
+		let quality = parameters.find_map(|parameter| parameter.strip_prefix("q=")).and_then(|quality| quality.parse::<f32>().ok()).unwrap_or(1.0);
+		(coding, quality)
+	}).collect();
+	let quality = |encoding: &str| {
+		let explicit = preferences.iter().find(|(coding, _)| coding.eq_ignore_ascii_case(encoding)).map(|(_, quality)| *quality);
+		let wildcard = preferences.iter().find(|(coding, _)| coding == "*").map(|(_, quality)| *quality);
+		match explicit.or(wildcard) {
+			Some(quality) => quality,
+			// below any explicitly accepted encoding
+			None if encoding.eq_ignore_ascii_case("identity") => f32::MIN_POSITIVE,
+			None => 0.0,
+		}
+	};
+
+	let mut best: Option<(&'a str, f32)> = None;
+	for encoding in available {
+		let quality = quality(encoding);
+		if quality > 0.0 && best.map_or(true, |(_, best_quality)| quality > best_quality) {
+			best = Some((encoding, quality));
+		}
+	}
+	best.map(|(encoding, _)| encoding)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::Read;
+
+	use flate2::read::{GzDecoder, ZlibDecoder};
+
+	use super::{negotiate_content_encoding, parse_compression_spec, SnapshotCompression};
+
+	#[test]
+	fn test_compression_file_extensions() {
+		assert_eq!(SnapshotCompression::Identity.file_extension(), "");
+		assert_eq!(SnapshotCompression::Gzip(6).file_extension(), ".gz");
+
+		let symlink_name = format!("1700000000.bin{}", SnapshotCompression::Gzip(6).file_extension());
+		assert_eq!(symlink_name, "1700000000.bin.gz");
+	}
+
+	#[test]
+	fn test_compression_round_trip() {
+		let data = vec![76, 68, 75, 1, 0, 0, 0, 0, 42, 42, 42, 42];
+		assert_eq!(SnapshotCompression::Identity.compress(&data), data);
+
+		let compressed = SnapshotCompression::Gzip(9).compress(&data);
+		let mut decompressed = Vec::new();
+		GzDecoder::new(&compressed[..]).read_to_end(&mut decompressed).unwrap();
+		assert_eq!(decompressed, data);
+
+		let compressed = SnapshotCompression::Deflate(9).compress(&data);
+		let mut decompressed = Vec::new();
+		ZlibDecoder::new(&compressed[..]).read_to_end(&mut decompressed).unwrap();
+		assert_eq!(decompressed, data);
+
+		#[cfg(feature = "brotli")]
+		{
+			let compressed = SnapshotCompression::Brotli(11).compress(&data);
+			let mut decompressed = Vec::new();
+			brotli::Decompressor::new(&compressed[..], 4096).read_to_end(&mut decompressed).unwrap();
+			assert_eq!(decompressed, data);
+		}
+	}
+
+	#[test]
+	fn test_compression_spec_parsing() {
+		assert_eq!(parse_compression_spec("none").unwrap(), vec![]);
+		assert_eq!(parse_compression_spec("gzip").unwrap(), vec![SnapshotCompression::Gzip(6)]);
+		assert_eq!(parse_compression_spec("GZIP:9").unwrap(), vec![SnapshotCompression::Gzip(9)]);
+		assert_eq!(parse_compression_spec("gzip,deflate:1").unwrap(), vec![SnapshotCompression::Gzip(6), SnapshotCompression::Deflate(1)]);
+
+		assert!(parse_compression_spec("gzip:10").is_err());
+		assert!(parse_compression_spec("gzip:fast").is_err());
+		assert!(parse_compression_spec("gzip:6,gzip:9").is_err());
+		assert!(parse_compression_spec("zstd:19").is_err());
+
+		#[cfg(feature = "brotli")]
+		{
+			assert_eq!(parse_compression_spec("gzip,br:11").unwrap(), vec![SnapshotCompression::Gzip(6), SnapshotCompression::Brotli(11)]);
+			assert_eq!(SnapshotCompression::Brotli(9).file_extension(), ".br");
+			assert!(parse_compression_spec("brotli:12").is_err());
+		}
+		#[cfg(not(feature = "brotli"))]
+		assert!(parse_compression_spec("br").is_err());
+	}
+
+	#[test]
+	fn test_content_encoding_negotiation() {
+		let available = ["identity", "gzip", "deflate"];
+		assert_eq!(negotiate_content_encoding("", &available), Some("identity"));
+		assert_eq!(negotiate_content_encoding("gzip, deflate", &available), Some("gzip"));
+		assert_eq!(negotiate_content_encoding("gzip;q=0.5, deflate", &available), Some("deflate"));
+		assert_eq!(negotiate_content_encoding("br", &available), Some("identity"));
+		assert_eq!(negotiate_content_encoding("br, identity;q=0", &available), None);
+		assert_eq!(negotiate_content_encoding("*;q=0.1, identity;q=0", &available), Some("gzip"));
+		assert_eq!(negotiate_content_encoding("gzip;q=0.8, br", &["identity", "gzip", "br"]), Some("br"));
+	}
+}
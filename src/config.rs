@@ -1,9 +1,16 @@
+use crate::blocklist::Blocklist;
+use crate::client::UploadEncoding;
+use crate::compression::{parse_compression_spec, SnapshotCompression, SUPPORTED_COMPRESSIONS};
+use crate::hashing::{parse_hash_algorithm, HashAlgorithm, SUPPORTED_HASH_ALGORITHMS};
 use crate::hex_utils;
 
 use std::convert::TryInto;
 use std::env;
+use std::fmt;
+use std::fs;
 use std::io::Cursor;
 use std::net::{SocketAddr, ToSocketAddrs};
+use std::str::FromStr;
 use std::time::Duration;
 
 use bitcoin::Network;
@@ -17,6 +24,8 @@ use tokio_postgres::Config;
 
 pub(crate) const SCHEMA_VERSION: i32 = 13;
 pub(crate) const SYMLINK_GRANULARITY_INTERVAL: u32 = 3600; // 1 hour
+/// Jan 1, 2022, a few months before RGS server was released
+pub(crate) const SYMLINK_ANCHOR_TIMESTAMP: u64 = 1640995200;
 pub(crate) const MAX_SNAPSHOT_SCOPE: u32 = 3600 * 24 * 21; // three weeks
 // generate symlinks based on a 3-hour-granularity
 /// If the last update in either direction was more than six days ago, we send a reminder
@@ -29,37 +38,424 @@ pub(crate) const CHANNEL_REMINDER_AGE: Duration = Duration::from_secs(6 * 24 * 6
 pub(crate) const CONNECTED_PEER_ASSERTION_LIMIT: usize = 5;
 pub(crate) const DOWNLOAD_NEW_GOSSIP: bool = true;
 
+/// An environment variable whose value couldn't be parsed
+#[derive(Debug, PartialEq)]
+pub struct ConfigError {
+	/// The name of the environment variable
+	pub key: String,
+	/// The offending value
+	pub value: String,
+	/// A description of the values that are accepted
+	pub expected: &'static str,
+	/// Why exactly the value was rejected, if that's more specific than the expected format
+	pub reason: Option<String>,
+}
+
+impl ConfigError {
+	fn invalid(key: &str, value: String, expected: &'static str) -> Self {
+		Self { key: key.to_string(), value, expected, reason: None }
+	}
+
+	fn with_reason(mut self, reason: String) -> Self {
+		self.reason = Some(reason);
+		self
+	}
+}
+
+impl fmt::Display for ConfigError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "Invalid value {:?} for {}, expected {}", self.value, self.key, self.expected)?;
+		if let Some(reason) = &self.reason {
+			write!(f, " ({})", reason)?;
+		}
+		Ok(())
+	}
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Describes the values accepted for a type that is parsed from an environment variable
+trait ConfigValue: FromStr {
+	const EXPECTED: &'static str;
+}
+
+impl ConfigValue for bool { const EXPECTED: &'static str = "true or false"; }
+impl ConfigValue for u16 { const EXPECTED: &'static str = "an integer between 0 and 65535"; }
+impl ConfigValue for u32 { const EXPECTED: &'static str = "a non-negative 32-bit integer"; }
+impl ConfigValue for u64 { const EXPECTED: &'static str = "a non-negative 64-bit integer"; }
+impl ConfigValue for usize { const EXPECTED: &'static str = "a non-negative integer"; }
+
+/// Parse an environment variable, returning `None` if it's not set
+fn try_env_var<T: ConfigValue>(key: &str) -> Result<Option<T>, ConfigError> {
+	match env::var(key) {
+		Ok(value) => value.parse::<T>().map(Some).map_err(|_| ConfigError::invalid(key, value, T::EXPECTED)),
+		Err(env::VarError::NotPresent) => Ok(None),
+		Err(env::VarError::NotUnicode(value)) => Err(ConfigError::invalid(key, value.to_string_lossy().into_owned(), T::EXPECTED)),
+	}
+}
+
+fn env_var<T: ConfigValue>(key: &str) -> Option<T> {
+	or_panic(try_env_var(key))
+}
+
+fn or_panic<T>(result: Result<T, ConfigError>) -> T {
+	result.unwrap_or_else(|error| panic!("{}", error))
+}
+
+/// Parse every configuration variable that has a fixed format, returning the first invalid one.
+///
+/// Configuration is otherwise read lazily, so this should be called on startup to catch a
+/// misconfiguration before it surfaces hours into operation. Variables that reference files or
+/// are only read in certain setups (e.g. the upload URL) are not checked.
+pub(crate) fn validate() -> Result<(), ConfigError> {
+	const BOOLEAN_KEYS: [&str; 9] = [
+		"RAPID_GOSSIP_SYNC_SERVER_KEEP_PREVIOUS_SNAPSHOTS",
+		"RAPID_GOSSIP_SYNC_SERVER_LOG_CYCLE_SUMMARY",
+		"RAPID_GOSSIP_SYNC_SERVER_CONTENT_ADDRESSED_FILENAMES",
+		"RAPID_GOSSIP_SYNC_SERVER_EXCLUDE_DISABLED_CHANNELS",
+		"RAPID_GOSSIP_SYNC_SERVER_REQUIRE_BIDIRECTIONAL_UPDATES",
+		"RAPID_GOSSIP_SYNC_SERVER_SCID_INDEX",
+		"RAPID_GOSSIP_SYNC_SERVER_SKIP_UNCHANGED_UPLOADS",
+		"RAPID_GOSSIP_SYNC_SERVER_GENERATE_EMPTY_DELTA",
+		"RAPID_GOSSIP_SYNC_SERVER_LATEST_SYMLINK",
+	];
+	const U64_KEYS: [&str; 8] = [
+		"RAPID_GOSSIP_SYNC_SERVER_INITIAL_SNAPSHOT_DELAY",
+		"RAPID_GOSSIP_SYNC_SERVER_DAILY_SNAPSHOTS",
+		"RAPID_GOSSIP_SYNC_SERVER_WEEKLY_SNAPSHOTS",
+		"RAPID_GOSSIP_SYNC_SERVER_INITIAL_SYNC_SCOPE",
+		"RAPID_GOSSIP_SYNC_SERVER_FULL_SYNC_HORIZON",
+		"RAPID_GOSSIP_SYNC_SERVER_SYMLINK_TIME_BUDGET",
+		"RAPID_GOSSIP_SYNC_SERVER_MIN_UPLOAD_INTERVAL",
+		"RAPID_GOSSIP_SYNC_SERVER_CAPTURE_DELAY_ALERT_THRESHOLD",
+	];
+	for key in BOOLEAN_KEYS {
+		try_env_var::<bool>(key)?;
+	}
+	for key in U64_KEYS {
+		try_env_var::<u64>(key)?;
+	}
+	try_env_var::<usize>("RAPID_GOSSIP_SYNC_SERVER_MAX_SNAPSHOT_BYTES")?;
+	try_env_var::<u16>("BITCOIN_REST_PORT")?;
+	try_snapshot_generation_interval()?;
+	try_symlink_anchor_timestamp()?;
+	try_snapshot_scopes_override()?;
+	try_snapshot_compressions()?;
+	try_hash_algorithm()?;
+	try_symlink_target_path()?;
+	try_symlink_threads()?;
+	try_cycle_duration_alert_percent()?;
+	try_max_graph_shrink_percent()?;
+	try_max_graph_shrink_refusals()?;
+	try_worker_threads()?;
+	try_network()?;
+	try_log_level()?;
+	try_upload_encoding()?;
+	try_upload_content_encoding()?;
+	try_upload_from_file()?;
+	try_upload_scopes()?;
+	try_blocklist()?;
+	try_upload_api_key()?;
+	Ok(())
+}
+
 pub(crate) fn snapshot_generation_interval() -> u32 {
-	let interval = env::var("RAPID_GOSSIP_SYNC_SERVER_SNAPSHOT_INTERVAL").unwrap_or(SYMLINK_GRANULARITY_INTERVAL.to_string())
-		.parse::<u32>()
-		.expect("RAPID_GOSSIP_SYNC_SERVER_SNAPSHOT_INTERVAL env variable must be a u32.");
-	assert!(interval > 0, "RAPID_GOSSIP_SYNC_SERVER_SNAPSHOT_INTERVAL must be positive");
-	assert_eq!(interval % SYMLINK_GRANULARITY_INTERVAL, 0, "RAPID_GOSSIP_SYNC_SERVER_SNAPSHOT_INTERVAL must be a multiple of {} (seconds)", SYMLINK_GRANULARITY_INTERVAL);
-	interval
+	or_panic(try_snapshot_generation_interval())
+}
+
+/// Defaults to the symlink granularity, so that configuring only the latter changes both
+fn try_snapshot_generation_interval() -> Result<u32, ConfigError> {
+	const KEY: &str = "RAPID_GOSSIP_SYNC_SERVER_SNAPSHOT_INTERVAL";
+	let granularity = try_symlink_granularity_interval()?;
+	let interval = try_env_var::<u32>(KEY)?.unwrap_or(granularity);
+	if interval == 0 || interval % granularity != 0 {
+		return Err(ConfigError::invalid(KEY, interval.to_string(), "a positive multiple of the symlink granularity (seconds)"));
+	}
+	Ok(interval)
+}
+
+/// The step of the timestamp grid symlinks are created on. Symlinks are created for every
+/// multiple of it after the anchor timestamp, so changing it drops the timestamps that aren't
+/// multiples of the new granularity, which clients may have cached.
+pub(crate) fn symlink_granularity_interval() -> u32 {
+	or_panic(try_symlink_granularity_interval())
+}
+
+fn try_symlink_granularity_interval() -> Result<u32, ConfigError> {
+	const KEY: &str = "RAPID_GOSSIP_SYNC_SERVER_SYMLINK_GRANULARITY";
+	let granularity = try_env_var::<u32>(KEY)?.unwrap_or(SYMLINK_GRANULARITY_INTERVAL);
+	if granularity == 0 {
+		return Err(ConfigError::invalid(KEY, granularity.to_string(), "a positive number of seconds"));
+	}
+	Ok(granularity)
+}
+
+/// The timestamp after which symlinks are created, which must lie on the symlink grid
+pub(crate) fn symlink_anchor_timestamp() -> u64 {
+	or_panic(try_symlink_anchor_timestamp())
+}
+
+fn try_symlink_anchor_timestamp() -> Result<u64, ConfigError> {
+	const KEY: &str = "RAPID_GOSSIP_SYNC_SERVER_SYMLINK_ANCHOR";
+	let anchor = try_env_var::<u64>(KEY)?.unwrap_or(SYMLINK_ANCHOR_TIMESTAMP);
+	if anchor % try_symlink_granularity_interval()? as u64 != 0 {
+		return Err(ConfigError::invalid(KEY, anchor.to_string(), "a multiple of the symlink granularity"));
+	}
+	Ok(anchor)
+}
+
+/// How long to wait before the first snapshot cycle, after the initial gossip sync has completed
+pub(crate) fn initial_snapshot_delay() -> Duration {
+	let seconds = env_var::<u64>("RAPID_GOSSIP_SYNC_SERVER_INITIAL_SNAPSHOT_DELAY").unwrap_or(0);
+	Duration::from_secs(seconds)
+}
+
+/// An explicit comma-separated list of the scopes, in seconds, to generate snapshots for, instead
+/// of doubling the snapshot interval up to three weeks. Timestamps are served the smallest
+/// listed scope covering them, falling back to the full sync, which is always generated.
+pub(crate) fn snapshot_scopes_override() -> Option<Vec<u64>> {
+	or_panic(try_snapshot_scopes_override())
+}
+
+fn try_snapshot_scopes_override() -> Result<Option<Vec<u64>>, ConfigError> {
+	const KEY: &str = "RAPID_GOSSIP_SYNC_SERVER_SNAPSHOT_SCOPES";
+	let scopes = match env::var(KEY) {
+		Ok(scopes) => scopes,
+		Err(_) => return Ok(None),
+	};
+	let parsed_scopes = scopes.split(',').map(|scope| scope.trim().parse::<u64>().ok().filter(|scope| *scope > 0)).collect::<Option<Vec<u64>>>();
+	match parsed_scopes {
+		Some(parsed_scopes) => Ok(Some(parsed_scopes)),
+		None => Err(ConfigError::invalid(KEY, scopes, "a comma-separated list of positive integers")),
+	}
+}
+
+/// How many daily scopes (one day, two days, …) to generate snapshots for. If this or the weekly
+/// count is set, the scopes are built from days and weeks rather than by doubling, with the count
+/// that isn't set defaulting to 6 daily or 3 weekly scopes.
+pub(crate) fn daily_snapshot_count() -> Option<u64> {
+	env_var::<u64>("RAPID_GOSSIP_SYNC_SERVER_DAILY_SNAPSHOTS")
+}
+
+/// How many weekly scopes to generate snapshots for, beyond the largest daily one
+pub(crate) fn weekly_snapshot_count() -> Option<u64> {
+	env_var::<u64>("RAPID_GOSSIP_SYNC_SERVER_WEEKLY_SNAPSHOTS")
+}
+
+/// The snapshot scope served to clients requesting a sync from timestamp 0.
+///
+/// This defaults to the full sync. Overriding it with a shorter scope means that new clients
+/// will never learn about channels that haven't seen any gossip within that scope, so it should
+/// only be used for experimentation.
+pub(crate) fn initial_sync_scope() -> Option<u64> {
+	env_var::<u64>("RAPID_GOSSIP_SYNC_SERVER_INITIAL_SYNC_SCOPE")
+}
+
+/// How far back, in seconds, a channel's latest update may be for it to be included in the full
+/// sync. Channels the full sync omits are still included in smaller scopes if they see updates.
+pub(crate) fn full_sync_horizon() -> Option<u64> {
+	env_var::<u64>("RAPID_GOSSIP_SYNC_SERVER_FULL_SYNC_HORIZON")
+}
+
+/// The maximum wall-clock time to spend creating symlinks per snapshot cycle
+pub(crate) fn symlink_time_budget() -> Option<Duration> {
+	env_var::<u64>("RAPID_GOSSIP_SYNC_SERVER_SYMLINK_TIME_BUDGET").map(Duration::from_secs)
+}
+
+/// How many worker threads the runtime uses, and thus how many cores snapshot serialization and
+/// gossip processing may occupy at once. Defaults to one per core.
+pub(crate) fn worker_threads() -> Option<usize> {
+	or_panic(try_worker_threads())
+}
+
+fn try_worker_threads() -> Result<Option<usize>, ConfigError> {
+	const KEY: &str = "RAPID_GOSSIP_SYNC_SERVER_WORKER_THREADS";
+	match try_env_var::<usize>(KEY)? {
+		Some(0) => Err(ConfigError::invalid(KEY, "0".to_string(), "a positive integer")),
+		threads => Ok(threads),
+	}
+}
+
+/// How many blocking threads create the symlinks of each snapshot cycle
+pub(crate) fn symlink_threads() -> usize {
+	or_panic(try_symlink_threads())
+}
+
+fn try_symlink_threads() -> Result<usize, ConfigError> {
+	const KEY: &str = "RAPID_GOSSIP_SYNC_SERVER_SYMLINK_THREADS";
+	match try_env_var::<usize>(KEY)? {
+		Some(0) => Err(ConfigError::invalid(KEY, "0".to_string(), "a positive integer")),
+		threads => Ok(threads.unwrap_or(1)),
+	}
+}
+
+/// The percentage of the snapshot interval a cycle may take before it's reported as being at risk
+/// of overrunning the interval
+pub(crate) fn cycle_duration_alert_percent() -> u64 {
+	or_panic(try_cycle_duration_alert_percent())
+}
+
+fn try_cycle_duration_alert_percent() -> Result<u64, ConfigError> {
+	const KEY: &str = "RAPID_GOSSIP_SYNC_SERVER_CYCLE_DURATION_ALERT_PERCENT";
+	let percent = try_env_var::<u64>(KEY)?.unwrap_or(75);
+	if percent == 0 {
+		return Err(ConfigError::invalid(KEY, percent.to_string(), "a positive percentage"));
+	}
+	Ok(percent)
+}
+
+/// How long after its reference timestamp a cycle may capture its snapshots before it's flagged
+pub(crate) fn capture_delay_alert_threshold() -> Duration {
+	Duration::from_secs(env_var::<u64>("RAPID_GOSSIP_SYNC_SERVER_CAPTURE_DELAY_ALERT_THRESHOLD").unwrap_or(300))
+}
+
+/// The maximum size of a serialized snapshot. If any scope exceeds it, the cycle is abandoned
+/// and the previously finalized snapshots are left in place.
+pub(crate) fn max_snapshot_bytes() -> Option<usize> {
+	env_var::<usize>("RAPID_GOSSIP_SYNC_SERVER_MAX_SNAPSHOT_BYTES")
+}
+
+/// By how many percent the full snapshot's channel count may drop from one cycle to the next.
+/// Beyond that, the cycle is abandoned, because such a drop is more likely to be caused by a
+/// broken gossip feed than by the network actually shrinking.
+pub(crate) fn max_graph_shrink_percent() -> Option<u64> {
+	or_panic(try_max_graph_shrink_percent())
+}
+
+fn try_max_graph_shrink_percent() -> Result<Option<u64>, ConfigError> {
+	const KEY: &str = "RAPID_GOSSIP_SYNC_SERVER_MAX_GRAPH_SHRINK_PERCENT";
+	let percent = try_env_var::<u64>(KEY)?;
+	if let Some(percent) = percent.filter(|percent| *percent == 0 || *percent > 100) {
+		return Err(ConfigError::invalid(KEY, percent.to_string(), "a percentage between 1 and 100"));
+	}
+	Ok(percent)
+}
+
+/// After how many consecutive cycles abandoned for exceeding the maximum graph shrinkage the
+/// shrunk graph is accepted as the new baseline, so that a lasting drop doesn't stall the
+/// snapshots until a restart
+pub(crate) fn max_graph_shrink_refusals() -> u32 {
+	or_panic(try_max_graph_shrink_refusals())
+}
+
+fn try_max_graph_shrink_refusals() -> Result<u32, ConfigError> {
+	const KEY: &str = "RAPID_GOSSIP_SYNC_SERVER_MAX_GRAPH_SHRINK_REFUSALS";
+	match try_env_var::<u32>(KEY)? {
+		Some(0) => Err(ConfigError::invalid(KEY, "0".to_string(), "a positive integer")),
+		refusals => Ok(refusals.unwrap_or(3)),
+	}
+}
+
+/// The compressed variants written in addition to the uncompressed snapshots
+pub(crate) fn snapshot_compressions() -> Vec<SnapshotCompression> {
+	or_panic(try_snapshot_compressions())
+}
+
+fn try_snapshot_compressions() -> Result<Vec<SnapshotCompression>, ConfigError> {
+	const KEY: &str = "RAPID_GOSSIP_SYNC_SERVER_SNAPSHOT_COMPRESSION";
+	let spec = env::var(KEY).unwrap_or("none".to_string());
+	parse_compression_spec(&spec).map_err(|error| ConfigError::invalid(KEY, spec, SUPPORTED_COMPRESSIONS).with_reason(error))
+}
+
+/// The digest identifying snapshot contents in uploads and content-addressed filenames
+pub(crate) fn hash_algorithm() -> HashAlgorithm {
+	or_panic(try_hash_algorithm())
+}
+
+fn try_hash_algorithm() -> Result<HashAlgorithm, ConfigError> {
+	const KEY: &str = "RAPID_GOSSIP_SYNC_SERVER_HASH_ALGORITHM";
+	let name = env::var(KEY).unwrap_or("sha256".to_string());
+	parse_hash_algorithm(&name).ok_or_else(|| ConfigError::invalid(KEY, name, SUPPORTED_HASH_ALGORITHMS))
+}
+
+/// The path of a file listing node pubkeys and short channel IDs to omit from all snapshots
+pub(crate) fn blocklist_path() -> Option<String> {
+	env::var("RAPID_GOSSIP_SYNC_SERVER_BLOCKLIST_PATH").ok()
+}
+
+/// Load the configured blocklist once, so that a missing or malformed file is rejected at startup
+/// rather than when the first snapshot is serialized
+fn try_blocklist() -> Result<(), ConfigError> {
+	match blocklist_path() {
+		Some(path) => Blocklist::load(&path).map(|_| ())
+			.map_err(|error| ConfigError::invalid("RAPID_GOSSIP_SYNC_SERVER_BLOCKLIST_PATH", path, "the path of a valid blocklist file").with_reason(error)),
+		None => Ok(()),
+	}
+}
+
+/// Whether to log a table summarizing all scopes at the end of each snapshot cycle
+pub(crate) fn log_cycle_summary() -> bool {
+	env_var::<bool>("RAPID_GOSSIP_SYNC_SERVER_LOG_CYCLE_SUMMARY").unwrap_or(false)
+}
+
+/// Whether snapshot files should be named after a hash of their contents rather than their
+/// generation and sync timestamps
+pub(crate) fn content_addressed_filenames() -> bool {
+	env_var::<bool>("RAPID_GOSSIP_SYNC_SERVER_CONTENT_ADDRESSED_FILENAMES").unwrap_or(false)
+}
+
+/// Whether to link an empty snapshot for the reference timestamp itself, which is what clients
+/// that are already up to date request. Without it, serving those clients is up to the front-end.
+pub(crate) fn generate_empty_delta() -> bool {
+	env_var::<bool>("RAPID_GOSSIP_SYNC_SERVER_GENERATE_EMPTY_DELTA").unwrap_or(true)
+}
+
+/// Whether to link the current full snapshot as `latest.lngossip` in the symlink directory, for
+/// tooling that wants a fixed name
+pub(crate) fn latest_symlink() -> bool {
+	env_var::<bool>("RAPID_GOSSIP_SYNC_SERVER_LATEST_SYMLINK").unwrap_or(false)
+}
+
+/// Whether to write an index of the short channel IDs each snapshot covers next to it, with the
+/// snapshot's file name plus `.scids`, so that clients can decide whether a delta is worth fetching
+pub(crate) fn scid_index() -> bool {
+	env_var::<bool>("RAPID_GOSSIP_SYNC_SERVER_SCID_INDEX").unwrap_or(false)
+}
+
+/// Whether channels that are disabled in both directions should be omitted from snapshots
+pub(crate) fn exclude_disabled_channels() -> bool {
+	env_var::<bool>("RAPID_GOSSIP_SYNC_SERVER_EXCLUDE_DISABLED_CHANNELS").unwrap_or(false)
+}
+
+/// Whether channels are only included in snapshots once updates for both directions are known.
+///
+/// Leaving this off serves channels with a single known direction as they are, which lets clients
+/// route through them in that direction, but some pathfinders treat the missing direction as a
+/// sign that the channel is unusable, or assume defaults for it.
+pub(crate) fn require_bidirectional_updates() -> bool {
+	env_var::<bool>("RAPID_GOSSIP_SYNC_SERVER_REQUIRE_BIDIRECTIONAL_UPDATES").unwrap_or(false)
 }
 
 pub(crate) fn network() -> Network {
-	let network = env::var("RAPID_GOSSIP_SYNC_SERVER_NETWORK").unwrap_or("bitcoin".to_string()).to_lowercase();
-	match network.as_str() {
-		"mainnet" => Network::Bitcoin,
-		"bitcoin" => Network::Bitcoin,
-		"testnet" => Network::Testnet,
-		"signet" => Network::Signet,
-		"regtest" => Network::Regtest,
-		_ => panic!("Invalid network"),
+	or_panic(try_network())
+}
+
+fn try_network() -> Result<Network, ConfigError> {
+	let network = env::var("RAPID_GOSSIP_SYNC_SERVER_NETWORK").unwrap_or("bitcoin".to_string());
+	match network.to_lowercase().as_str() {
+		"mainnet" => Ok(Network::Bitcoin),
+		"bitcoin" => Ok(Network::Bitcoin),
+		"testnet" => Ok(Network::Testnet),
+		"signet" => Ok(Network::Signet),
+		"regtest" => Ok(Network::Regtest),
+		_ => Err(ConfigError::invalid("RAPID_GOSSIP_SYNC_SERVER_NETWORK", network, "one of mainnet, bitcoin, testnet, signet, or regtest")),
 	}
 }
 
 pub(crate) fn log_level() -> lightning::util::logger::Level {
-	let level = env::var("RAPID_GOSSIP_SYNC_SERVER_LOG_LEVEL").unwrap_or("info".to_string()).to_lowercase();
-	match level.as_str() {
-		"gossip" => lightning::util::logger::Level::Gossip,
-		"trace" => lightning::util::logger::Level::Trace,
-		"debug" => lightning::util::logger::Level::Debug,
-		"info" => lightning::util::logger::Level::Info,
-		"warn" => lightning::util::logger::Level::Warn,
-		"error" => lightning::util::logger::Level::Error,
-		_ => panic!("Invalid log level"),
+	or_panic(try_log_level())
+}
+
+fn try_log_level() -> Result<lightning::util::logger::Level, ConfigError> {
+	let level = env::var("RAPID_GOSSIP_SYNC_SERVER_LOG_LEVEL").unwrap_or("info".to_string());
+	match level.to_lowercase().as_str() {
+		"gossip" => Ok(lightning::util::logger::Level::Gossip),
+		"trace" => Ok(lightning::util::logger::Level::Trace),
+		"debug" => Ok(lightning::util::logger::Level::Debug),
+		"info" => Ok(lightning::util::logger::Level::Info),
+		"warn" => Ok(lightning::util::logger::Level::Warn),
+		"error" => Ok(lightning::util::logger::Level::Error),
+		_ => Err(ConfigError::invalid("RAPID_GOSSIP_SYNC_SERVER_LOG_LEVEL", level, "one of gossip, trace, debug, info, warn, or error")),
 	}
 }
 
@@ -67,24 +463,183 @@ pub(crate) fn network_graph_cache_path() -> String {
 	format!("{}/network_graph.bin", cache_path())
 }
 
+/// The persisted network graph to initialize from at startup. This defaults to the one the server
+/// caches itself, which keeps being written to the cache path either way.
+pub(crate) fn network_graph_path() -> String {
+	env::var("RAPID_GOSSIP_SYNC_SERVER_NETWORK_GRAPH_PATH").unwrap_or_else(|_| network_graph_cache_path())
+}
+
 pub(crate) fn cache_path() -> String {
 	let path = env::var("RAPID_GOSSIP_SYNC_SERVER_CACHES_PATH").unwrap_or("./res".to_string()).to_lowercase();
 	path
 }
 
+/// Whether the previously finalized snapshot set should be retained under `<cache_path>/previous`
+/// when a new set is promoted, so that there's always a complete set to fall back to.
+pub(crate) fn keep_previous_snapshots() -> bool {
+	env_var::<bool>("RAPID_GOSSIP_SYNC_SERVER_KEEP_PREVIOUS_SNAPSHOTS").unwrap_or(false)
+}
+
+/// The directory symlinks point into for their snapshot files, either relative to the symlink
+/// directory or absolute. Note that with an absolute path, the symlinks of a retained previous
+/// snapshot set point into the current set.
+pub(crate) fn symlink_target_path() -> String {
+	or_panic(try_symlink_target_path())
+}
+
+fn try_symlink_target_path() -> Result<String, ConfigError> {
+	const KEY: &str = "RAPID_GOSSIP_SYNC_SERVER_SYMLINK_TARGET_PATH";
+	let path = env::var(KEY).unwrap_or("../snapshots".to_string());
+	let trimmed_path = path.trim_end_matches('/');
+	if trimmed_path.is_empty() {
+		return Err(ConfigError::invalid(KEY, path, "a non-empty directory path"));
+	}
+	Ok(trimmed_path.to_string())
+}
+
+/// A shell command to run after every successfully finalized snapshot cycle
+pub(crate) fn post_snapshot_command() -> Option<String> {
+	env::var("RAPID_GOSSIP_SYNC_SERVER_POST_SNAPSHOT_COMMAND").ok()
+}
+
+/// The minimum time between the starts of two successful uploads. Cycles finishing sooner after
+/// the last successful upload skip uploading, but still write the snapshot files.
+pub(crate) fn min_upload_interval() -> Option<Duration> {
+	env_var::<u64>("RAPID_GOSSIP_SYNC_SERVER_MIN_UPLOAD_INTERVAL").map(Duration::from_secs)
+}
+
+/// Whether uploading the full snapshot should be skipped if it's byte-identical to the last one
+/// that was uploaded successfully, which is common on quiet graphs. The snapshot files are still
+/// written every cycle.
+pub(crate) fn skip_unchanged_uploads() -> bool {
+	env_var::<bool>("RAPID_GOSSIP_SYNC_SERVER_SKIP_UNCHANGED_UPLOADS").unwrap_or(false)
+}
+
+/// The path of a Unix domain socket every finalized snapshot is additionally pushed to
+pub(crate) fn snapshot_socket_path() -> Option<String> {
+	env::var("RAPID_GOSSIP_SYNC_SERVER_SNAPSHOT_SOCKET").ok()
+}
+
 pub(crate) fn cert_path() -> String {
 	let path = env::var("DB_CERT").unwrap_or("db.crt".to_string()).to_lowercase();
 	path
 }
 
-pub(crate) fn upload_api_key() -> Option<String> {
-	env::var("RAPID_GOSSIP_SYNC_UPLOAD_API_KEY").ok()
+/// The scopes whose snapshots are uploaded, as a comma-separated list of scopes in seconds, in
+/// which `full` stands for the full snapshot, which is the only one uploaded by default. Listed
+/// scopes that aren't generated are ignored.
+pub(crate) fn upload_scopes() -> Vec<u64> {
+	or_panic(try_upload_scopes())
+}
+
+fn try_upload_scopes() -> Result<Vec<u64>, ConfigError> {
+	const KEY: &str = "RAPID_GOSSIP_SYNC_SERVER_UPLOAD_SCOPES";
+	let scopes = match env::var(KEY) {
+		Ok(scopes) => scopes,
+		Err(_) => return Ok(vec![u64::MAX]),
+	};
+	let parsed_scopes = scopes.split(',').map(str::trim).filter(|scope| !scope.is_empty()).map(|scope| match scope {
+		"full" => Some(u64::MAX),
+		_ => scope.parse::<u64>().ok().filter(|scope| *scope > 0),
+	}).collect::<Option<Vec<u64>>>();
+	parsed_scopes.ok_or_else(|| ConfigError::invalid(KEY, scopes, "a comma-separated list of positive integers or full"))
+}
+
+/// The upload API key, which is read from the file at RAPID_GOSSIP_SYNC_UPLOAD_API_KEY_FILE if
+/// set, and otherwise taken from RAPID_GOSSIP_SYNC_UPLOAD_API_KEY. The latter may also reference a
+/// file (e.g. a mounted secret) in the form `file:<path>`.
+///
+/// Files are re-read on every call, so that rotated secrets are picked up, which also means that
+/// a file that is briefly missing during a rotation yields an error rather than a key.
+pub(crate) fn try_upload_api_key() -> Result<Option<String>, ConfigError> {
+	if let Ok(path) = env::var("RAPID_GOSSIP_SYNC_UPLOAD_API_KEY_FILE") {
+		return read_secret_file("RAPID_GOSSIP_SYNC_UPLOAD_API_KEY_FILE", &path).map(Some);
+	}
+	let api_key = match env::var("RAPID_GOSSIP_SYNC_UPLOAD_API_KEY") {
+		Ok(api_key) => api_key,
+		Err(_) => return Ok(None),
+	};
+	match api_key.strip_prefix("file:") {
+		Some(path) => read_secret_file("RAPID_GOSSIP_SYNC_UPLOAD_API_KEY", path).map(Some),
+		None => Ok(Some(api_key)),
+	}
+}
+
+/// How a secret is shown in logs, which reveals whether it is set, but nothing about its value
+pub(crate) fn redact_secret(secret: Option<&str>) -> &'static str {
+	match secret {
+		Some(_) => "****",
+		None => "not set",
+	}
+}
+
+fn read_secret_file(key: &str, path: &str) -> Result<String, ConfigError> {
+	let secret = fs::read_to_string(path)
+		.map_err(|error| ConfigError::invalid(key, path.to_string(), "the path of a readable secret file").with_reason(error.to_string()))?;
+	Ok(secret.trim_end_matches(['\r', '\n']).to_string())
+}
+
+pub(crate) fn upload_user_agent() -> String {
+	env::var("RAPID_GOSSIP_SYNC_UPLOAD_USER_AGENT").unwrap_or(format!("rapid-gossip-sync-server/{}", env!("CARGO_PKG_VERSION")))
+}
+
+pub(crate) fn upload_request_id_prefix() -> String {
+	env::var("RAPID_GOSSIP_SYNC_UPLOAD_REQUEST_ID_PREFIX").unwrap_or("rgs".to_string())
 }
 
 pub(crate) fn upload_url() -> String {
 	env::var("RAPID_GOSSIP_SYNC_UPLOAD_URL").expect("needs url if going to upload")
 }
 
+/// Whether the full snapshot should be uploaded by streaming its written file, rather than from
+/// memory, which requires the raw upload encoding
+pub(crate) fn upload_from_file() -> bool {
+	or_panic(try_upload_from_file())
+}
+
+fn try_upload_from_file() -> Result<bool, ConfigError> {
+	const KEY: &str = "RAPID_GOSSIP_SYNC_UPLOAD_FROM_FILE";
+	let upload_from_file = try_env_var::<bool>(KEY)?.unwrap_or(false);
+	if upload_from_file && try_upload_encoding()? != UploadEncoding::Raw {
+		return Err(ConfigError::invalid(KEY, "true".to_string(), "false unless the upload encoding is raw"));
+	}
+	Ok(upload_from_file)
+}
+
+pub(crate) fn upload_encoding() -> UploadEncoding {
+	or_panic(try_upload_encoding())
+}
+
+fn try_upload_encoding() -> Result<UploadEncoding, ConfigError> {
+	let encoding = env::var("RAPID_GOSSIP_SYNC_UPLOAD_ENCODING").unwrap_or("json".to_string());
+	match encoding.to_lowercase().as_str() {
+		"json" => Ok(UploadEncoding::Json),
+		"binary" => Ok(UploadEncoding::Binary),
+		"raw" => Ok(UploadEncoding::Raw),
+		_ => Err(ConfigError::invalid("RAPID_GOSSIP_SYNC_UPLOAD_ENCODING", encoding, "one of json, binary, or raw")),
+	}
+}
+
+/// The `Content-Encoding` of uploads. Anything but identity uploads the matching compressed
+/// snapshot file as is, and thus requires both the raw upload encoding and that compression.
+pub(crate) fn upload_content_encoding() -> &'static str {
+	or_panic(try_upload_content_encoding())
+}
+
+fn try_upload_content_encoding() -> Result<&'static str, ConfigError> {
+	const KEY: &str = "RAPID_GOSSIP_SYNC_UPLOAD_CONTENT_ENCODING";
+	let content_encoding = env::var(KEY).unwrap_or("identity".to_string());
+	if content_encoding.eq_ignore_ascii_case("identity") {
+		return Ok("identity");
+	}
+	let compression = try_snapshot_compressions()?.into_iter().find(|compression| compression.content_encoding().eq_ignore_ascii_case(&content_encoding));
+	match compression {
+		Some(_) if try_upload_encoding()? != UploadEncoding::Raw => Err(ConfigError::invalid(KEY, content_encoding, "identity unless the upload encoding is raw")),
+		Some(compression) => Ok(compression.content_encoding()),
+		None => Err(ConfigError::invalid(KEY, content_encoding, "identity or one of the snapshot compressions")),
+	}
+}
+
 pub(crate) fn db_connection_config() -> Config {
 	let mut config = Config::new();
 	let env_name_prefix = if cfg!(test) {
@@ -109,10 +664,7 @@ pub(crate) fn db_connection_config() -> Config {
 
 pub(crate) fn bitcoin_rest_endpoint() -> HttpEndpoint {
 	let host = env::var("BITCOIN_REST_DOMAIN").unwrap_or("127.0.0.1".to_string());
-	let port = env::var("BITCOIN_REST_PORT")
-		.unwrap_or("8332".to_string())
-		.parse::<u16>()
-		.expect("BITCOIN_REST_PORT env variable must be a u16.");
+	let port = env_var::<u16>("BITCOIN_REST_PORT").unwrap_or(8332);
 	let path = env::var("BITCOIN_REST_PATH").unwrap_or("/rest/".to_string());
 	HttpEndpoint::for_host(host).with_port(port).with_path(path)
 }
@@ -344,7 +896,7 @@ fn resolve_peer_info(peer_info: &str) -> Result<(PublicKey, SocketAddr), &str> {
 
 #[cfg(test)]
 mod tests {
-	use super::resolve_peer_info;
+	use super::{read_secret_file, redact_secret, resolve_peer_info, try_env_var, ConfigError};
 	use bitcoin::hashes::hex::ToHex;
 
 	#[test]
@@ -365,4 +917,30 @@ mod tests {
 		let socket_address = socket_address.to_string();
 		assert!(socket_address == "127.0.0.1:9735" || socket_address == "[::1]:9735");
 	}
+	#[test]
+	fn test_config_error() {
+		let key = "RAPID_GOSSIP_SYNC_SERVER_TEST_CONFIG_ERROR";
+		assert_eq!(try_env_var::<u64>(key), Ok(None));
+
+		std::env::set_var(key, "42");
+		assert_eq!(try_env_var::<u64>(key), Ok(Some(42)));
+
+		std::env::set_var(key, "forty-two");
+		let error = try_env_var::<u64>(key).unwrap_err();
+		assert_eq!(error, ConfigError { key: key.to_string(), value: "forty-two".to_string(), expected: "a non-negative 64-bit integer", reason: None });
+		assert_eq!(error.to_string(), "Invalid value \"forty-two\" for RAPID_GOSSIP_SYNC_SERVER_TEST_CONFIG_ERROR, expected a non-negative 64-bit integer");
+		std::env::remove_var(key);
+	}
+
+	#[test]
+	fn test_secret_redaction() {
+		assert_eq!(redact_secret(Some("api-key")), "****");
+		assert_eq!(redact_secret(Some("")), "****");
+		assert_eq!(redact_secret(None), "not set");
+
+		// a missing secret file is reported rather than panicking mid-cycle
+		let error = read_secret_file("RAPID_GOSSIP_SYNC_UPLOAD_API_KEY_FILE", "/nonexistent/api-key").unwrap_err();
+		assert_eq!(error.key, "RAPID_GOSSIP_SYNC_UPLOAD_API_KEY_FILE");
+		assert!(error.reason.is_some());
+	}
 }
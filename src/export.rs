@@ -0,0 +1,66 @@
+use bitcoin::hashes::hex::ToHex;
+use lightning::util::ser::Writeable;
+use serde_json::{json, Map, Value};
+
+use crate::serialization::{decode_snapshot, DecodedUpdate, DefaultUpdateValues};
+
+/// Snapshots larger than this are only exported to JSON if forced, because the output is roughly
+/// an order of magnitude larger than the snapshot.
+pub const MAX_JSON_EXPORT_SNAPSHOT_BYTES: usize = 16 * 1024 * 1024;
+
+/// Render a snapshot as JSON, for debugging purposes.
+///
+/// This decodes the snapshot as written rather than applying it to a network graph, so a delta's
+/// updates are listed even for channels it doesn't announce. Each channel lists its announcement
+/// fields, and each update its direction, whether it is incremental, and the properties it
+/// carries. Full updates also list the properties they take from the snapshot's default values,
+/// whereas incremental updates only list the ones that changed.
+pub fn snapshot_to_json(data: &[u8], force: bool) -> Result<Value, String> {
+	if data.len() > MAX_JSON_EXPORT_SNAPSHOT_BYTES && !force {
+		return Err(format!("Snapshot is {} bytes, refusing to export it without forcing", data.len()));
+	}
+
+	let snapshot = decode_snapshot(data).map_err(|error| format!("Failed to decode snapshot: {:?}", error))?;
+	let network = crate::genesis_network(&snapshot.chain_hash[..]).ok_or("Snapshot is for an unknown chain")?;
+
+	let channels: Vec<Value> = snapshot.announcements.iter().map(|announcement| json!({
+		"short_channel_id": announcement.short_channel_id,
+		// skip the length prefix
+		"features": announcement.features.encode()[2..].to_hex(),
+		"node_one": announcement.node_id_1.as_slice().to_hex(),
+		"node_two": announcement.node_id_2.as_slice().to_hex(),
+	})).collect();
+	let updates: Vec<Value> = snapshot.updates.iter()
+		.map(|update| update_to_json(update, snapshot.default_values.as_ref()))
+		.collect();
+
+	Ok(json!({
+		"network": network.to_string(),
+		"last_sync_timestamp": snapshot.latest_seen_timestamp,
+		"channels": channels,
+		"updates": updates,
+	}))
+}
+
+fn update_to_json(update: &DecodedUpdate, default_values: Option<&DefaultUpdateValues>) -> Value {
+	let mut fields = Map::new();
+	fields.insert("short_channel_id".to_string(), json!(update.short_channel_id));
+	let direction = if update.channel_flags & 1 == 0 { "one_to_two" } else { "two_to_one" };
+	fields.insert("direction".to_string(), json!(direction));
+	fields.insert("incremental".to_string(), json!(update.incremental));
+	fields.insert("enabled".to_string(), json!(update.channel_flags & 0b_0000_0010 == 0));
+
+	// incremental updates leave the properties they don't carry unchanged
+	let defaults = if update.incremental { None } else { default_values };
+	let mut insert = |key: &str, value: Option<Value>| {
+		if let Some(value) = value {
+			fields.insert(key.to_string(), value);
+		}
+	};
+	insert("cltv_expiry_delta", update.cltv_expiry_delta.or(defaults.map(|defaults| defaults.cltv_expiry_delta)).map(Value::from));
+	insert("htlc_minimum_msat", update.htlc_minimum_msat.or(defaults.map(|defaults| defaults.htlc_minimum_msat)).map(Value::from));
+	insert("htlc_maximum_msat", update.htlc_maximum_msat.or(defaults.map(|defaults| defaults.htlc_maximum_msat)).map(Value::from));
+	insert("fee_base_msat", update.fee_base_msat.or(defaults.map(|defaults| defaults.fee_base_msat)).map(Value::from));
+	insert("fee_proportional_millionths", update.fee_proportional_millionths.or(defaults.map(|defaults| defaults.fee_proportional_millionths)).map(Value::from));
+	Value::Object(fields)
+}
@@ -0,0 +1,71 @@
+use bitcoin::hashes::hex::ToHex;
+use bitcoin::hashes::{sha256, sha512, Hash};
+
+/// The digest snapshot contents are identified by, both in uploads and in content-addressed
+/// filenames.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HashAlgorithm {
+	Sha256,
+	Sha512,
+	/// Only available with the `blake3` feature
+	#[cfg(feature = "blake3")]
+	Blake3,
+}
+
+impl HashAlgorithm {
+	/// The name the algorithm is configured by, and announced as alongside its digests
+	pub fn name(&self) -> &'static str {
+		match self {
+			HashAlgorithm::Sha256 => "sha256",
+			HashAlgorithm::Sha512 => "sha512",
+			#[cfg(feature = "blake3")]
+			HashAlgorithm::Blake3 => "blake3",
+		}
+	}
+
+	/// The hex-encoded digest of `data`
+	pub fn hex_digest(&self, data: &[u8]) -> String {
+		match self {
+			HashAlgorithm::Sha256 => sha256::Hash::hash(data).to_hex(),
+			HashAlgorithm::Sha512 => sha512::Hash::hash(data).to_hex(),
+			#[cfg(feature = "blake3")]
+			HashAlgorithm::Blake3 => blake3::hash(data).to_hex().to_string(),
+		}
+	}
+}
+
+/// The algorithms [`parse_hash_algorithm`] accepts, as shown in configuration errors
+#[cfg(not(feature = "blake3"))]
+pub(crate) const SUPPORTED_HASH_ALGORITHMS: &str = "one of sha256 or sha512";
+#[cfg(feature = "blake3")]
+pub(crate) const SUPPORTED_HASH_ALGORITHMS: &str = "one of sha256, sha512 or blake3";
+
+pub(crate) fn parse_hash_algorithm(name: &str) -> Option<HashAlgorithm> {
+	match name.to_lowercase().as_str() {
+		"sha256" => Some(HashAlgorithm::Sha256),
+		"sha512" => Some(HashAlgorithm::Sha512),
+		#[cfg(feature = "blake3")]
+		"blake3" => Some(HashAlgorithm::Blake3),
+		_ => None,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{parse_hash_algorithm, HashAlgorithm};
+
+	#[test]
+	fn test_hash_algorithms() {
+		assert_eq!(parse_hash_algorithm("SHA256"), Some(HashAlgorithm::Sha256));
+		assert_eq!(parse_hash_algorithm("sha512"), Some(HashAlgorithm::Sha512));
+		assert_eq!(parse_hash_algorithm("md5"), None);
+
+		assert_eq!(HashAlgorithm::Sha256.hex_digest(b""), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+		assert_eq!(HashAlgorithm::Sha512.hex_digest(b"").len(), 128);
+
+		#[cfg(feature = "blake3")]
+		assert_eq!(HashAlgorithm::Blake3.hex_digest(b""), "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262");
+		#[cfg(not(feature = "blake3"))]
+		assert_eq!(parse_hash_algorithm("blake3"), None);
+	}
+}
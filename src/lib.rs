@@ -10,26 +10,32 @@
 extern crate core;
 
 use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs::{self, File};
-use std::io::BufReader;
+use std::io::{BufReader, Read};
 use std::ops::Deref;
-use std::sync::Arc;
-use lightning::log_info;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use lightning::{log_debug, log_error, log_info, log_warn};
 
+use bitcoin::{BlockHash, Network};
+use bitcoin::blockdata::constants::genesis_block;
 use lightning::routing::gossip::{NetworkGraph, NodeId};
 use lightning::util::logger::Logger;
-use lightning::util::ser::{ReadableArgs, Writeable};
+use lightning::ln::msgs::DecodeError;
+use lightning::util::ser::{Readable, ReadableArgs, Writeable, Writer};
 use serde::{Serialize, Deserialize};
-use tokio::sync::mpsc;
-use crate::config::SYMLINK_GRANULARITY_INTERVAL;
+use tokio::sync::{broadcast, mpsc};
 use tokio_postgres::Client;
 use crate::lookup::DeltaSet;
 use native_tls::{Certificate, TlsConnector};
 use postgres_native_tls::MakeTlsConnector;
 
+use crate::blocklist::Blocklist;
 use crate::persistence::GossipPersister;
 use crate::serialization::UpdateSerialization;
-use crate::snapshot::Snapshotter;
+use crate::client::UploadCredentials;
+use crate::snapshot::{CacheLock, Snapshotter, SnapshotterConfig, SNAPSHOT_EVENT_CAPACITY};
 use crate::types::RGSSLogger;
 
 mod downloader;
@@ -42,21 +48,82 @@ mod config;
 mod hex_utils;
 mod verifier;
 mod client;
+mod compression;
+mod blocklist;
+mod export;
+mod socket;
+mod hashing;
 
 pub mod types;
 
+pub use crate::client::{UploadOutcome, Uploader};
+pub use crate::compression::negotiate_content_encoding;
+pub use crate::config::ConfigError;
+pub use crate::hashing::HashAlgorithm;
+pub use crate::export::{snapshot_to_json, MAX_JSON_EXPORT_SNAPSHOT_BYTES};
+pub use crate::snapshot::{calendar_snapshot_scopes, content_addressed_filename, resolve_snapshot, scope_last_sync_timestamp, snapshot_filename, snapshot_scopes, snapshot_sync_timestamps, symlink_plan, verify_served_tree, SnapshotEvent, SnapshotScopeStats, SnapshotTransform, TreeProblem};
+
 #[cfg(test)]
 mod tests;
 
+/// The version of the rapid gossip sync serialization format emitted by this server
+pub const FORMAT_VERSION: u8 = 1;
+
+/// Check all environment variables with a fixed format, returning the first malformed one.
+///
+/// Configuration is read lazily, so a malformed value would otherwise only be noticed, by way of
+/// a panic, once it's first used.
+pub fn validate_config() -> Result<(), ConfigError> {
+	config::validate()
+}
+
+/// Build the multi-threaded runtime the server is meant to be run on, with the configured number
+/// of worker threads.
+pub fn build_runtime() -> std::io::Result<tokio::runtime::Runtime> {
+	let mut builder = tokio::runtime::Builder::new_multi_thread();
+	builder.enable_all();
+	if let Some(worker_threads) = config::worker_threads() {
+		builder.worker_threads(worker_threads);
+	}
+	builder.build()
+}
+
+/// Check the finalized snapshot tree in the configured cache path with [`verify_served_tree`],
+/// using the configured scopes, and requiring an update within the last two snapshot intervals.
+pub fn verify_finalized_snapshots() -> Vec<TreeProblem> {
+	let snapshot_interval = config::snapshot_generation_interval() as u64;
+	let snapshot_scopes = snapshot::configured_snapshot_scopes(snapshot_interval);
+	// generation falls back to the full sync for unknown initial sync scopes, too
+	let initial_sync_scope = config::initial_sync_scope().filter(|scope| snapshot_scopes.contains(scope)).unwrap_or(u64::MAX);
+	let max_update_age = std::time::Duration::from_secs(2 * snapshot_interval);
+	verify_served_tree(&config::cache_path(), config::symlink_granularity_interval() as u64, &snapshot_scopes, initial_sync_scope, config::generate_empty_delta(), max_update_age)
+}
+
+/// Rebuild the symlinks of the finalized snapshot set in the configured cache path from the
+/// snapshot filenames, without regenerating any snapshots, returning how many were created.
+///
+/// Content-addressed snapshot files can't be symlinked this way, because their names don't
+/// record the scope they were generated for.
+pub fn rebuild_finalized_symlinks<L: Deref>(logger: L) -> Result<u64, String> where L::Target: Logger {
+	let snapshotter_config = SnapshotterConfig::from_config();
+	let _cache_lock = CacheLock::acquire(&snapshotter_config.cache_path)?;
+	snapshot::rebuild_symlinks(&snapshotter_config, &logger)
+}
+
 /// The purpose of this prefix is to identify the serialization format, should other rapid gossip
 /// sync formats arise in the future.
 ///
 /// The fourth byte is the protocol version in case our format gets updated.
-const GOSSIP_PREFIX: [u8; 4] = [76, 68, 75, 1];
+const GOSSIP_PREFIX: [u8; 4] = [76, 68, 75, FORMAT_VERSION];
 
 pub struct RapidSyncProcessor<L: Deref> where L::Target: Logger {
 	network_graph: Arc<NetworkGraph<L>>,
-	logger: L
+	logger: L,
+	snapshot_events: broadcast::Sender<SnapshotEvent>,
+	snapshot_transform: Option<SnapshotTransform>,
+	uploader: UploadCredentials,
+	startup: Instant,
+	time_to_first_snapshot: Arc<Mutex<Option<Duration>>>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -67,35 +134,307 @@ pub struct SerializedResponse {
 	pub update_count: u32,
 	pub update_count_full: u32,
 	pub update_count_incremental: u32,
+	/// The hex-encoded hash of `data`, as computed by [`content_hash`]. This isn't part of
+	/// the binary encoding, which is decoded by hashing the data again.
+	pub content_hash: String,
+	/// The block height a [`serialize_delta_since_block_height`] snapshot was serialized from.
+	/// Such snapshots have the same format version as timestamp-based ones, so this is the only
+	/// thing telling them apart. It isn't part of the binary encoding, which decodes it as `None`.
+	#[serde(default)]
+	pub last_sync_block_height: Option<u32>,
+}
+
+/// The hex-encoded hash of a snapshot's data using the configured [`HashAlgorithm`], which
+/// identifies it in uploads
+pub fn content_hash(data: &[u8]) -> String {
+	config::hash_algorithm().hex_digest(data)
+}
+
+/// Why a snapshot couldn't be serialized
+#[derive(Debug)]
+pub enum SerializeError {
+	/// The gossip stored in the database couldn't be queried
+	Database(String),
+	/// The configured blocklist file couldn't be reloaded
+	Blocklist(String),
+}
+
+impl fmt::Display for SerializeError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			SerializeError::Database(error) => write!(f, "Failed to query the database: {}", error),
+			SerializeError::Blocklist(error) => write!(f, "Failed to reload the blocklist: {}", error),
+		}
+	}
+}
+
+impl std::error::Error for SerializeError {}
+
+/// The cost of serializing a single scope, as measured by
+/// [`RapidSyncProcessor::benchmark_serialization`]
+#[derive(Clone, Debug, Serialize)]
+pub struct SerializationBenchmark {
+	pub scope: u64,
+	pub last_sync_timestamp: u64,
+	pub duration_secs: f64,
+	pub size_bytes: usize,
+	pub message_count: u32,
+	pub announcement_count: u32,
+	pub update_count: u32,
+}
+
+/// A compact alternative to the JSON upload encoding, used with the `application/octet-stream`
+/// content type.
+///
+/// The layout is a big-endian `u64` length, followed by that many bytes of snapshot data, followed
+/// by the message, announcement, update, full update, and incremental update counts as big-endian
+/// `u32`s, in that order.
+impl Writeable for SerializedResponse {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), std::io::Error> {
+		(self.data.len() as u64).write(writer)?;
+		writer.write_all(&self.data)?;
+		self.message_count.write(writer)?;
+		self.announcement_count.write(writer)?;
+		self.update_count.write(writer)?;
+		self.update_count_full.write(writer)?;
+		self.update_count_incremental.write(writer)
+	}
+}
+
+impl Readable for SerializedResponse {
+	fn read<R: Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		let data_length: u64 = Readable::read(reader)?;
+		let mut data = Vec::new();
+		reader.take(data_length).read_to_end(&mut data)?;
+		if data.len() as u64 != data_length {
+			return Err(DecodeError::ShortRead);
+		}
+		Ok(Self {
+			content_hash: content_hash(&data),
+			data,
+			message_count: Readable::read(reader)?,
+			announcement_count: Readable::read(reader)?,
+			update_count: Readable::read(reader)?,
+			update_count_full: Readable::read(reader)?,
+			update_count_incremental: Readable::read(reader)?,
+			last_sync_block_height: None,
+		})
+	}
 }
 
 impl<L: Deref + Clone + Send + Sync + 'static> RapidSyncProcessor<L> where L::Target: Logger {
 	pub fn new(logger: L) -> Self {
 		let network = config::network();
-		let network_graph = if let Ok(file) = File::open(&config::network_graph_cache_path()) {
+		let network_graph_path = config::network_graph_path();
+		let network_graph = if fs::metadata(&network_graph_path).is_ok() {
 			log_info!(logger, "Initializing from cached network graph…");
-			let mut buffered_reader = BufReader::new(file);
-			let network_graph_result = NetworkGraph::read(&mut buffered_reader, logger.clone());
-			if let Ok(network_graph) = network_graph_result {
-				log_info!(logger, "Initialized from cached network graph!");
-				network_graph
-			} else {
-				log_info!(logger, "Initialization from cached network graph failed: {}", network_graph_result.err().unwrap());
-				NetworkGraph::new(network, logger.clone())
+			match load_network_graph(&network_graph_path, network, logger.clone()) {
+				Ok(network_graph) => {
+					log_info!(logger, "Initialized from cached network graph!");
+					network_graph
+				}
+				Err(error) => {
+					log_info!(logger, "Initialization from cached network graph failed: {}", error);
+					NetworkGraph::new(network, logger.clone())
+				}
 			}
 		} else {
 			NetworkGraph::new(network, logger.clone())
 		};
 		let arc_network_graph = Arc::new(network_graph);
+		let (snapshot_events, _) = broadcast::channel(SNAPSHOT_EVENT_CAPACITY);
 		Self {
 			network_graph: arc_network_graph,
-			logger
+			logger,
+			snapshot_events,
+			snapshot_transform: None,
+			uploader: UploadCredentials::Configured,
+			startup: Instant::now(),
+			time_to_first_snapshot: Arc::new(Mutex::new(None)),
+		}
+	}
+
+	/// Transform the contents of every snapshot file before it is written, see
+	/// [`SnapshotTransform`].
+	pub fn set_snapshot_transform(&mut self, transform: SnapshotTransform) {
+		self.snapshot_transform = Some(transform);
+	}
+
+	/// Upload full snapshots through `uploader` with `api_key`, instead of to the configured upload
+	/// URL with the configured API key.
+	pub fn set_uploader(&mut self, uploader: Arc<dyn Uploader>, api_key: String) {
+		self.uploader = UploadCredentials::Fixed(uploader, api_key);
+	}
+
+	fn snapshotter(&self) -> Snapshotter<L> {
+		Snapshotter::new(Arc::clone(&self.network_graph), self.logger.clone(), self.snapshot_events.clone())
+			.with_transform(self.snapshot_transform)
+			.with_uploader(self.uploader.clone())
+			.with_startup(self.startup, Arc::clone(&self.time_to_first_snapshot))
+	}
+
+	/// How long after this processor was created the first set of snapshots was finalized, or
+	/// `None` if that hasn't happened yet, e. g. to hold back traffic until snapshots are ready.
+	pub fn time_to_first_snapshot(&self) -> Option<Duration> {
+		*self.time_to_first_snapshot.lock().unwrap()
+	}
+
+	/// Subscribe to the events published after each completed snapshot cycle.
+	///
+	/// Every subscriber receives every event, but one that falls behind by more than a few cycles
+	/// will skip the oldest ones (see [`broadcast::Receiver::recv`]) rather than delay generation.
+	pub fn subscribe_snapshot_events(&self) -> broadcast::Receiver<SnapshotEvent> {
+		self.snapshot_events.subscribe()
+	}
+
+	/// Sync gossip and generate snapshots indefinitely.
+	///
+	/// The cache path is locked before anything else happens, so that a second instance pointed
+	/// at the same cache path fails right away rather than after syncing into the same database.
+	/// Only returns if that lock can't be acquired.
+	pub async fn start_sync(&self) -> Result<(), String> {
+		let snapshotter = self.snapshotter();
+		// held for as long as the service runs
+		let _cache_lock = CacheLock::acquire(&snapshotter.config().cache_path)?;
+
+		self.sync_gossip().await;
+
+		// start the gossip snapshotting service
+		snapshotter.snapshot_gossip().await;
+		Ok(())
+	}
+
+	/// Catch up on gossip like [`Self::start_sync`], but then generate a single set of snapshots
+	/// and return, for use with external schedulers.
+	///
+	/// Returns the completed cycle's event, or `None` if it was abandoned or the cache path is
+	/// locked by another instance. Note that if gossip is being downloaded, the download tasks keep
+	/// running on the runtime after this returns.
+	pub async fn run_once(&self) -> Option<SnapshotEvent> {
+		let snapshotter = self.snapshotter();
+		let _cache_lock = match CacheLock::acquire(&snapshotter.config().cache_path) {
+			Ok(cache_lock) => cache_lock,
+			Err(error) => {
+				log_error!(self.logger, "{}", error);
+				return None;
+			}
+		};
+
+		self.sync_gossip().await;
+
+		snapshotter.generate_snapshots().await
+	}
+
+	/// Upload every snapshot in the finalized set to the configured upload endpoint, each under
+	/// the timestamp it is a delta from, e. g. to backfill a new endpoint without waiting for the
+	/// next cycle. Uploads through the same uploader as the cycles, including one set with
+	/// [`Self::set_uploader`].
+	///
+	/// Only timestamp-named snapshot files can be uploaded, because the timestamp is taken from
+	/// the file name. Returns the number of uploaded snapshots, stopping at the first failure.
+	pub fn reupload_finalized_snapshots(&self) -> anyhow::Result<usize> {
+		let (uploader, api_key) = self.uploader.resolve()?.ok_or(anyhow::anyhow!("No upload API key configured"))?;
+		let snapshot_directory = format!("{}/snapshots", config::cache_path());
+
+		let mut snapshot_files = Vec::new();
+		for entry in fs::read_dir(&snapshot_directory)? {
+			let filename = entry?.file_name().to_string_lossy().to_string();
+			match snapshot::parse_snapshot_filename(&filename) {
+				Some((_, scope, last_sync_timestamp)) => snapshot_files.push((scope, last_sync_timestamp, filename)),
+				None => log_info!(self.logger, "Not re-uploading {}", filename),
+			}
+		}
+		snapshot_files.sort_unstable();
+
+		for (scope, last_sync_timestamp, filename) in &snapshot_files {
+			let data = fs::read(format!("{}/{}", snapshot_directory, filename))?;
+			let snapshot = serialization::read_serialized_response(data)
+				.map_err(|error| anyhow::anyhow!("Failed to read {}: {:?}", filename, error))?;
+			let outcome = uploader.post_snapshot(snapshot, *last_sync_timestamp, api_key.clone())?;
+			log_info!(self.logger, "Re-uploaded {}-second snapshot {} for timestamp {} (status {})", scope, filename, last_sync_timestamp, outcome.status);
+		}
+		Ok(snapshot_files.len())
+	}
+
+	/// Serialize every configured scope from the current network graph and the gossip stored in
+	/// the database, timing each, in order to assess the cost of scope and filter changes without
+	/// running the daemon.
+	///
+	/// This takes the same serialization path as the snapshot cycles, but doesn't sync gossip
+	/// first, and doesn't persist anything.
+	pub async fn benchmark_serialization(&self) -> Result<Vec<SerializationBenchmark>, SerializeError> {
+		let serialization_config = SerializationConfig::from_config();
+		let snapshot_interval = serialization_config.snapshot_interval;
+		let snapshot_scopes = snapshot::configured_snapshot_scopes(snapshot_interval);
+		let current_timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+		let reference_timestamp = current_timestamp - current_timestamp % snapshot_interval;
+
+		let mut benchmarks = Vec::with_capacity(snapshot_scopes.len());
+		for (scope, last_sync_timestamp) in snapshot::snapshot_sync_timestamps(reference_timestamp, &snapshot_scopes) {
+			let start = Instant::now();
+			let snapshot = if scope == u64::MAX {
+				serialize_full_graph_with_config(Arc::clone(&self.network_graph), reference_timestamp, &serialization_config, self.logger.clone()).await?
+			} else {
+				serialize_delta(Arc::clone(&self.network_graph), last_sync_timestamp as u32, &serialization_config, self.logger.clone()).await?
+			};
+			benchmarks.push(SerializationBenchmark {
+				scope,
+				last_sync_timestamp,
+				duration_secs: start.elapsed().as_secs_f64(),
+				size_bytes: snapshot.data.len(),
+				message_count: snapshot.message_count,
+				announcement_count: snapshot.announcement_count,
+				update_count: snapshot.update_count,
+			});
+		}
+		Ok(benchmarks)
+	}
+
+	/// Log the configuration the snapshots are generated and uploaded with, after resolving
+	/// defaults and overrides, with secrets redacted
+	fn log_effective_config(&self) {
+		let snapshot_interval = config::snapshot_generation_interval() as u64;
+		let scopes: Vec<String> = snapshot::configured_snapshot_scopes(snapshot_interval).into_iter().map(|scope| {
+			if scope == u64::MAX { "full".to_string() } else { scope.to_string() }
+		}).collect();
+		log_info!(self.logger, "Effective configuration:");
+		log_info!(self.logger, "  format version: {}", FORMAT_VERSION);
+		log_info!(self.logger, "  network: {}", config::network());
+		log_info!(self.logger, "  cache path: {}", config::cache_path());
+		log_info!(self.logger, "  snapshot interval: {}s", snapshot_interval);
+		log_info!(self.logger, "  symlink grid: every {}s after {}", config::symlink_granularity_interval(), config::symlink_anchor_timestamp());
+		log_info!(self.logger, "  snapshot scopes: {}", scopes.join(", "));
+		log_info!(self.logger, "  snapshot compression: {:?}", config::snapshot_compressions());
+		log_info!(self.logger, "  hash algorithm: {}", config::hash_algorithm().name());
+		let api_key = match config::try_upload_api_key() {
+			Ok(api_key) => api_key,
+			Err(error) => {
+				log_warn!(self.logger, "  upload API key: {}", error);
+				None
+			}
+		};
+		match (&self.uploader, &api_key) {
+			(UploadCredentials::Fixed(..), _) => log_info!(self.logger, "  uploads: custom uploader"),
+			(UploadCredentials::Configured, Some(_)) => {
+				log_info!(self.logger, "  upload URL: {}", config::upload_url());
+				log_info!(self.logger, "  upload API key: {}", config::redact_secret(api_key.as_deref()));
+				log_info!(self.logger, "  upload encoding: {:?}, content encoding: {}", config::upload_encoding(), config::upload_content_encoding());
+			}
+			(UploadCredentials::Configured, None) => log_info!(self.logger, "  uploads: disabled"),
 		}
 	}
 
-	pub async fn start_sync(&self) {
-		log_info!(self.logger, "Starting Rapid Gossip Sync Server");
-		log_info!(self.logger, "Snapshot interval: {} seconds", config::snapshot_generation_interval());
+	/// Wait until the initial gossip sync has completed, leaving gossip download and persistence
+	/// running in the background if enabled.
+	async fn sync_gossip(&self) {
+		log_info!(self.logger, "Starting Rapid Gossip Sync Server (format version {})", FORMAT_VERSION);
+		// resolve the config before syncing, so that an invalid value is rejected at startup
+		// rather than found out about once the initial sync has completed
+		self.log_effective_config();
+		let cache_path = config::cache_path();
+		if let Err(error) = snapshot::prepare_cache_path(&cache_path) {
+			panic!("Cache path {} is not usable: {}", cache_path, error);
+		}
 
 		// means to indicate sync completion status within this module
 		let (sync_completion_sender, mut sync_completion_receiver) = mpsc::channel::<()>(1);
@@ -117,27 +456,55 @@ impl<L: Deref + Clone + Send + Sync + 'static> RapidSyncProcessor<L> where L::Ta
 			panic!("Sync failed!");
 		}
 		log_info!(self.logger, "Initial sync complete!");
+	}
 
-		// start the gossip snapshotting service
-		Snapshotter::new(Arc::clone(&self.network_graph), self.logger.clone()).snapshot_gossip().await;
+	/// Generate a single set of snapshots from the current network graph and the gossip stored in
+	/// the database, returning once they have been finalized.
+	///
+	/// This is meant for synchronous embedders, and drives the generation on its own
+	/// current-thread Tokio runtime, so it must not be called from within a runtime.
+	///
+	/// Returns the completed cycle's event, or `None` if it was abandoned or the cache path is
+	/// locked by another instance.
+	pub fn generate_snapshots_blocking(&self) -> Option<SnapshotEvent> {
+		let snapshotter = self.snapshotter();
+		let _cache_lock = match CacheLock::acquire(&snapshotter.config().cache_path) {
+			Ok(cache_lock) => cache_lock,
+			Err(error) => {
+				log_error!(self.logger, "{}", error);
+				return None;
+			}
+		};
+
+		let runtime = tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.expect("Failed to build snapshot generation runtime");
+		runtime.block_on(snapshotter.generate_snapshots())
 	}
 }
 
 pub(crate) async fn connect_to_db() -> Client {
-    let cert = fs::read(config::cert_path()).expect("db cert should exist");
-    let cert = Certificate::from_pem(&cert).expect("db cert should parse");
+	try_connect_to_db().await.unwrap()
+}
+
+async fn try_connect_to_db() -> Result<Client, String> {
+    let cert = fs::read(config::cert_path()).map_err(|error| format!("db cert should exist: {}", error))?;
+    let cert = Certificate::from_pem(&cert).map_err(|error| format!("db cert should parse: {}", error))?;
     let connector = TlsConnector::builder()
         .add_root_certificate(cert)
         .build()
-        .expect("db cert should build");
+        .map_err(|error| format!("db cert should build: {}", error))?;
     let connector = MakeTlsConnector::new(connector);
 
 	let connection_config = config::db_connection_config();
-	let (client, connection) = connection_config.connect(connector).await.unwrap();
+	let (client, connection) = connection_config.connect(connector).await.map_err(|error| error.to_string())?;
 
 	tokio::spawn(async move {
+		// the client's pending and subsequent queries fail with an error instead, which callers
+		// that can recover from a lost connection, like serialization, handle
 		if let Err(e) = connection.await {
-			panic!("connection error: {}", e);
+			eprintln!("connection error: {}", e);
 		}
 	});
 
@@ -149,11 +516,12 @@ pub(crate) async fn connect_to_db() -> Client {
 		client.execute(&format!("SET search_path TO {}", schema_name), &[]).await.unwrap();
 	}
 
-	client.execute("set time zone UTC", &[]).await.unwrap();
-	client
+	client.execute("set time zone UTC", &[]).await.map_err(|error| error.to_string())?;
+	Ok(client)
 }
 
-/// This method generates a no-op blob that can be used as a delta where none exists.
+/// This method generates a no-op blob for the chain identified by `chain_hash`, which clients will
+/// reject if it doesn't match their own, that can be used as a delta where none exists.
 ///
 /// The primary purpose of this method is the scenario of a client retrieving and processing a
 /// given snapshot, and then immediately retrieving the would-be next snapshot at the timestamp
@@ -163,19 +531,15 @@ pub(crate) async fn connect_to_db() -> Client {
 ///
 /// In principle, this method could also be used to address another unfortunately all too common
 /// pitfall: requesting snapshots from intermediate timestamps, i. e. those that are not multiples
-/// of our granularity constant. Note that for that purpose, this method could be very dangerous,
+/// of our symlink granularity. Note that for that purpose, this method could be very dangerous,
 /// because if consumed, the `timestamp` value calculated here will overwrite the timestamp that
 /// the client previously had, which could result in duplicated or omitted gossip down the line.
-fn serialize_empty_blob(current_timestamp: u64) -> Vec<u8> {
-	let mut blob = GOSSIP_PREFIX.to_vec();
-
-	let network = config::network();
-	let genesis_block = bitcoin::blockdata::constants::genesis_block(network);
-	let chain_hash = genesis_block.block_hash();
-	chain_hash.write(&mut blob).unwrap();
-
-	let blob_timestamp = Snapshotter::<Arc<RGSSLogger>>::round_down_to_nearest_multiple(current_timestamp, SYMLINK_GRANULARITY_INTERVAL as u64) as u32;
-	blob_timestamp.write(&mut blob).unwrap();
+///
+/// The blob shares the prefix, and thus the format version, of regular snapshots, so it must be
+/// kept in sync with any changes to the format.
+fn serialize_empty_blob(current_timestamp: u64, granularity_interval: u64, chain_hash: BlockHash) -> Vec<u8> {
+	let blob_timestamp = Snapshotter::<Arc<RGSSLogger>>::round_down_to_nearest_multiple(current_timestamp, granularity_interval) as u32;
+	let mut blob = snapshot_header(chain_hash, blob_timestamp);
 
 	0u32.write(&mut blob).unwrap(); // node count
 	0u32.write(&mut blob).unwrap(); // announcement count
@@ -184,13 +548,163 @@ fn serialize_empty_blob(current_timestamp: u64) -> Vec<u8> {
 	blob
 }
 
-async fn serialize_delta<L: Deref + Clone>(network_graph: Arc<NetworkGraph<L>>, last_sync_timestamp: u32, logger: L) -> SerializedResponse where L::Target: Logger {
-	let client = connect_to_db().await;
+/// The no-op blob the server serves where no delta exists yet, for client test suites exercising
+/// the "nothing changed" path.
+///
+/// It has the current format version and `network`'s chain hash, no nodes, announcements, or
+/// updates, and `timestamp` rounded down to the symlink granularity as its latest seen timestamp.
+/// Applying it leaves a client's graph unchanged, and only reports that rounded timestamp as the
+/// one to request the next delta from. Because it is produced by the same serializer as the served
+/// blob, it changes along with the format version.
+pub fn empty_delta_blob(network: Network, timestamp: u64) -> Vec<u8> {
+	serialize_empty_blob(timestamp, config::symlink_granularity_interval() as u64, genesis_block(network).block_hash())
+}
+
+/// The network whose genesis block has the given hash, if it's one of the known ones
+pub(crate) fn genesis_network(genesis_hash: &[u8]) -> Option<Network> {
+	[Network::Bitcoin, Network::Testnet, Network::Signet, Network::Regtest].into_iter()
+		.find(|network| genesis_block(*network).block_hash()[..] == *genesis_hash)
+}
+
+/// Read a network graph persisted in LDK's format from `path`, e. g. to generate snapshots from a
+/// known graph rather than from live gossip. Graphs for a network other than `network` are
+/// rejected.
+pub fn load_network_graph<L: Deref>(path: &str, network: Network, logger: L) -> anyhow::Result<NetworkGraph<L>> where L::Target: Logger {
+	let mut buffered_reader = BufReader::new(File::open(path)?);
+	let network_graph = NetworkGraph::read(&mut buffered_reader, logger)
+		.map_err(|error| anyhow::anyhow!("Failed to read network graph from {}: {}", path, error))?;
+	if network_graph.get_genesis_hash() != genesis_block(network).block_hash() {
+		return Err(anyhow::anyhow!("Network graph at {} is not for {}", path, network));
+	}
+	Ok(network_graph)
+}
+
+/// The start of every snapshot: the prefix including the format version, the chain hash, and the
+/// latest seen timestamp. Both real snapshots and empty blobs are started with this, so that they
+/// can't diverge when the format changes.
+fn snapshot_header(chain_hash: BlockHash, latest_seen_timestamp: u32) -> Vec<u8> {
+	let mut header = GOSSIP_PREFIX.to_vec();
+	chain_hash.write(&mut header).unwrap();
+	latest_seen_timestamp.write(&mut header).unwrap();
+	header
+}
+
+/// Serialize the entire network graph into a rapid gossip sync snapshot, as consumed by clients
+/// without any prior sync.
+///
+/// All integers are big-endian, and the layout is as follows:
+/// - the four-byte prefix, i. e. `LDK` followed by the format version byte
+/// - the 32-byte chain hash
+/// - a `u32` timestamp of the latest gossip included, rounded down to the snapshot interval
+/// - a `u32` node ID count, followed by that many 33-byte compressed node IDs
+/// - a `u32` channel announcement count, followed by that many announcements, each consisting of
+///   the length-prefixed channel features, the `BigSize` short channel ID delta from the
+///   previous announcement, and the `BigSize` indices of both nodes into the node ID list
+/// - a `u32` channel update count; if nonzero, followed by the default update values (`u16` CLTV
+///   expiry delta, `u64` HTLC minimum, `u32` base fee, `u32` proportional fee, `u64` HTLC maximum)
+/// - that many channel updates, each consisting of the `BigSize` short channel ID delta from the
+///   previous update, a flags byte, and those of the five values that differ from the defaults,
+///   as indicated by the flags
+///
+/// If a full sync horizon is configured, channels without any update within it of the current
+/// time are omitted.
+pub async fn serialize_full_graph<L: Deref + Clone>(network_graph: Arc<NetworkGraph<L>>, logger: L) -> Result<SerializedResponse, SerializeError> where L::Target: Logger {
+	let current_timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+	serialize_full_graph_with_config(network_graph, current_timestamp, &SerializationConfig::from_config(), logger).await
+}
+
+/// Like [`serialize_full_graph`], but with the full sync horizon counted back from
+/// `reference_timestamp`
+async fn serialize_full_graph_with_config<L: Deref + Clone>(network_graph: Arc<NetworkGraph<L>>, reference_timestamp: u64, serialization_config: &SerializationConfig, logger: L) -> Result<SerializedResponse, SerializeError> where L::Target: Logger {
+	let filters = DeltaFilters {
+		min_last_update: serialization_config.full_sync_horizon.map(|horizon| reference_timestamp.saturating_sub(horizon) as u32),
+		..Default::default()
+	};
+	serialize_delta_filtered(network_graph, 0, filters, serialization_config, logger).await
+}
+
+/// Serialize the channels confirmed after `last_sync_block_height`, for clients that track their
+/// sync progress by block height rather than by timestamp.
+///
+/// Because a client tracking heights presumably doesn't know any of these channels yet, the
+/// result is a regular snapshot with the same format version as a full sync, containing the
+/// announcements and full updates of only the newly confirmed channels. Its counts only reflect
+/// those channels, and its [`SerializedResponse::last_sync_block_height`] marks the mode.
+pub async fn serialize_delta_since_block_height<L: Deref + Clone>(network_graph: Arc<NetworkGraph<L>>, last_sync_block_height: u32, logger: L) -> Result<SerializedResponse, SerializeError> where L::Target: Logger {
+	let filters = DeltaFilters { last_sync_block_height: Some(last_sync_block_height), ..Default::default() };
+	serialize_delta_filtered(network_graph, 0, filters, &SerializationConfig::from_config(), logger).await
+}
+
+async fn serialize_delta<L: Deref + Clone>(network_graph: Arc<NetworkGraph<L>>, last_sync_timestamp: u32, serialization_config: &SerializationConfig, logger: L) -> Result<SerializedResponse, SerializeError> where L::Target: Logger {
+	serialize_delta_filtered(network_graph, last_sync_timestamp, DeltaFilters::default(), serialization_config, logger).await
+}
+
+/// Serialize a delta from exactly `last_sync_timestamp`, rather than from the start of one of the
+/// scheduled scopes, e. g. to compare what a client reporting that timestamp should receive
+/// against what its symlink serves it.
+pub async fn serialize_delta_since<L: Deref + Clone>(network_graph: Arc<NetworkGraph<L>>, last_sync_timestamp: u32, filters: DeltaFilters, logger: L) -> Result<SerializedResponse, SerializeError> where L::Target: Logger {
+	serialize_delta_filtered(network_graph, last_sync_timestamp, filters, &SerializationConfig::from_config(), logger).await
+}
+
+/// Serialize the gossip seen within `[start_timestamp, end_timestamp]`, as if the delta from
+/// `start_timestamp` had been generated at `end_timestamp`, for auditing what changed within a
+/// specific period.
+///
+/// Channels announced after the window are omitted, and so are reminders, which depend on the
+/// time of serialization rather than on the window. Clients aren't meant to consume these.
+pub async fn serialize_delta_between<L: Deref + Clone>(network_graph: Arc<NetworkGraph<L>>, start_timestamp: u32, end_timestamp: u32, filters: DeltaFilters, logger: L) -> Result<SerializedResponse, SerializeError> where L::Target: Logger {
+	let filters = DeltaFilters { max_seen: Some(end_timestamp), ..filters };
+	serialize_delta_filtered(network_graph, start_timestamp, filters, &SerializationConfig::from_config(), logger).await
+}
+
+/// Restrictions on the channels included in a snapshot, on top of the configured ones
+#[derive(Clone, Debug, Default)]
+pub struct DeltaFilters {
+	/// Only include channels confirmed after this block height
+	pub last_sync_block_height: Option<u32>,
+	/// Only include channels with an update in either direction at or after this timestamp
+	pub min_last_update: Option<u32>,
+	/// Only include gossip the server saw at or before this timestamp
+	pub max_seen: Option<u32>,
+	/// Only include channels with at least one of these nodes as an endpoint, unless empty
+	pub node_ids: HashSet<NodeId>,
+}
+
+/// The settings snapshots are serialized with, each resolved once from the configuration function
+/// of the same name
+#[derive(Clone, Debug)]
+pub(crate) struct SerializationConfig {
+	/// The latest seen timestamp of every snapshot is rounded down to a multiple of this
+	pub(crate) snapshot_interval: u64,
+	pub(crate) full_sync_horizon: Option<u64>,
+	pub(crate) exclude_disabled_channels: bool,
+	pub(crate) require_bidirectional_updates: bool,
+	/// Reloaded for every snapshot, so that changes apply without a restart
+	pub(crate) blocklist_path: Option<String>,
+	/// Computes the content hashes of the snapshots
+	pub(crate) hash_algorithm: HashAlgorithm,
+}
+
+impl SerializationConfig {
+	pub(crate) fn from_config() -> Self {
+		Self {
+			snapshot_interval: config::snapshot_generation_interval() as u64,
+			full_sync_horizon: config::full_sync_horizon(),
+			exclude_disabled_channels: config::exclude_disabled_channels(),
+			require_bidirectional_updates: config::require_bidirectional_updates(),
+			blocklist_path: config::blocklist_path(),
+			hash_algorithm: config::hash_algorithm(),
+		}
+	}
+}
+
+async fn serialize_delta_filtered<L: Deref + Clone>(network_graph: Arc<NetworkGraph<L>>, last_sync_timestamp: u32, filters: DeltaFilters, serialization_config: &SerializationConfig, logger: L) -> Result<SerializedResponse, SerializeError> where L::Target: Logger {
+	let client = try_connect_to_db().await.map_err(SerializeError::Database)?;
 
 	network_graph.remove_stale_channels_and_tracking();
 
 	let mut output: Vec<u8> = vec![];
-	let snapshot_interval = config::snapshot_generation_interval();
+	let snapshot_interval = serialization_config.snapshot_interval as u32;
 
 	// set a flag if the chain hash is prepended
 	// chain hash only necessary if either channel announcements or non-incremental updates are present
@@ -213,12 +727,38 @@ async fn serialize_delta<L: Deref + Clone>(network_graph: Arc<NetworkGraph<L>>,
 	};
 
 	let mut delta_set = DeltaSet::new();
-	lookup::fetch_channel_announcements(&mut delta_set, network_graph, &client, last_sync_timestamp, logger.clone()).await;
-	log_info!(logger, "announcement channel count: {}", delta_set.len());
-	lookup::fetch_channel_updates(&mut delta_set, &client, last_sync_timestamp, logger.clone()).await;
-	log_info!(logger, "update-fetched channel count: {}", delta_set.len());
+	lookup::fetch_channel_announcements(&mut delta_set, Arc::clone(&network_graph), &client, last_sync_timestamp, logger.clone()).await
+		.map_err(|error| SerializeError::Database(error.to_string()))?;
+	log_debug!(logger, "announcement channel count: {}", delta_set.len());
+	lookup::fetch_channel_updates(&mut delta_set, &client, last_sync_timestamp, filters.max_seen.unwrap_or(u32::MAX), logger.clone()).await
+		.map_err(|error| SerializeError::Database(error.to_string()))?;
+	log_debug!(logger, "update-fetched channel count: {}", delta_set.len());
 	lookup::filter_delta_set(&mut delta_set, logger.clone());
-	log_info!(logger, "update-filtered channel count: {}", delta_set.len());
+	log_debug!(logger, "update-filtered channel count: {}", delta_set.len());
+	if serialization_config.exclude_disabled_channels {
+		lookup::filter_disabled_channels(&mut delta_set, &network_graph, last_sync_timestamp, logger.clone());
+	}
+	if serialization_config.require_bidirectional_updates {
+		lookup::filter_unidirectional_channels(&mut delta_set, &network_graph, logger.clone());
+	}
+	if let Some(blocklist_path) = &serialization_config.blocklist_path {
+		// reloaded for every snapshot, so that changes apply without a restart, and validated at
+		// startup, so that a broken file only fails the snapshots if it's broken while running
+		let blocklist = Blocklist::load(blocklist_path).map_err(SerializeError::Blocklist)?;
+		lookup::filter_blocklisted(&mut delta_set, &network_graph, &blocklist, logger.clone());
+	}
+	if let Some(last_sync_block_height) = filters.last_sync_block_height {
+		lookup::filter_by_block_height(&mut delta_set, last_sync_block_height, logger.clone());
+	}
+	if let Some(min_last_update) = filters.min_last_update {
+		lookup::filter_stale_channels(&mut delta_set, &network_graph, min_last_update, logger.clone());
+	}
+	if let Some(max_seen) = filters.max_seen {
+		lookup::filter_seen_after(&mut delta_set, max_seen, logger.clone());
+	}
+	if !filters.node_ids.is_empty() {
+		lookup::filter_by_nodes(&mut delta_set, &network_graph, &filters.node_ids, logger.clone());
+	}
 	let serialization_details = serialization::serialize_delta_set(delta_set, last_sync_timestamp);
 
 	// process announcements
@@ -270,15 +810,11 @@ async fn serialize_delta<L: Deref + Clone>(network_graph: Arc<NetworkGraph<L>>,
 	// some stats
 	let message_count = announcement_count + update_count;
 
-	let mut prefixed_output = GOSSIP_PREFIX.to_vec();
-
-	// always write the chain hash
-	serialization_details.chain_hash.write(&mut prefixed_output).unwrap();
-	// always write the latest seen timestamp
+	// always write the chain hash and the latest seen timestamp
 	let latest_seen_timestamp = serialization_details.latest_seen;
 	let overflow_seconds = latest_seen_timestamp % snapshot_interval;
 	let serialized_seen_timestamp = latest_seen_timestamp.saturating_sub(overflow_seconds);
-	serialized_seen_timestamp.write(&mut prefixed_output).unwrap();
+	let mut prefixed_output = snapshot_header(serialization_details.chain_hash, serialized_seen_timestamp);
 
 	let node_id_count = node_ids.len() as u32;
 	node_id_count.write(&mut prefixed_output).unwrap();
@@ -289,15 +825,17 @@ async fn serialize_delta<L: Deref + Clone>(network_graph: Arc<NetworkGraph<L>>,
 
 	prefixed_output.append(&mut output);
 
-	log_info!(logger, "duplicated node ids: {}", duplicate_node_ids);
-	log_info!(logger, "latest seen timestamp: {:?}", serialization_details.latest_seen);
+	log_debug!(logger, "duplicated node ids: {}", duplicate_node_ids);
+	log_debug!(logger, "latest seen timestamp: {:?}", serialization_details.latest_seen);
 
-	SerializedResponse {
+	Ok(SerializedResponse {
+		content_hash: serialization_config.hash_algorithm.hex_digest(&prefixed_output),
 		data: prefixed_output,
 		message_count,
 		announcement_count,
 		update_count,
 		update_count_full,
 		update_count_incremental,
-	}
+		last_sync_block_height: filters.last_sync_block_height,
+	})
 }
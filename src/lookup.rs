@@ -5,14 +5,15 @@ use std::sync::Arc;
 use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 use lightning::ln::msgs::{ChannelAnnouncement, ChannelUpdate, UnsignedChannelAnnouncement, UnsignedChannelUpdate};
-use lightning::routing::gossip::NetworkGraph;
+use lightning::routing::gossip::{ChannelUpdateInfo, NetworkGraph, NodeId};
 use lightning::util::ser::Readable;
 use tokio_postgres::Client;
 
 use futures::StreamExt;
-use lightning::{log_gossip, log_info};
+use lightning::{log_debug, log_gossip, log_info};
 use lightning::util::logger::Logger;
 
+use crate::blocklist::Blocklist;
 use crate::config;
 use crate::serialization::MutatedProperties;
 
@@ -33,6 +34,9 @@ pub(super) struct UpdateDelta {
 pub(super) struct DirectedUpdateDelta {
 	pub(super) last_update_before_seen: Option<UpdateDelta>,
 	pub(super) mutated_properties: MutatedProperties,
+	/// The latest update in this direction since the last sync. This is the only update that is
+	/// serialized per direction, so multiple updates within one scope are deduplicated, and the
+	/// intermediate ones are only used to determine the mutated properties.
 	pub(super) latest_update_after_seen: Option<UpdateDelta>,
 	pub(super) serialization_update_flags: Option<u8>,
 }
@@ -71,11 +75,11 @@ impl Default for DirectedUpdateDelta {
 /// whether they had been seen before.
 /// Also include all announcements for which the first update was announced
 /// after `last_sync_timestamp`
-pub(super) async fn fetch_channel_announcements<L: Deref>(delta_set: &mut DeltaSet, network_graph: Arc<NetworkGraph<L>>, client: &Client, last_sync_timestamp: u32, logger: L) where L::Target: Logger {
-	log_info!(logger, "Obtaining channel ids from network graph");
+pub(super) async fn fetch_channel_announcements<L: Deref>(delta_set: &mut DeltaSet, network_graph: Arc<NetworkGraph<L>>, client: &Client, last_sync_timestamp: u32, logger: L) -> Result<(), tokio_postgres::Error> where L::Target: Logger {
+	log_debug!(logger, "Obtaining channel ids from network graph");
 	let channel_ids = {
 		let read_only_graph = network_graph.read_only();
-		log_info!(logger, "Retrieved read-only network graph copy");
+		log_debug!(logger, "Retrieved read-only network graph copy");
 		let channel_iterator = read_only_graph.channels().unordered_iter();
 		channel_iterator
 			.filter(|c| c.1.announcement_message.is_some())
@@ -83,18 +87,18 @@ pub(super) async fn fetch_channel_announcements<L: Deref>(delta_set: &mut DeltaS
 			.collect::<Vec<_>>()
 	};
 	#[cfg(test)]
-	log_info!(logger, "Channel IDs: {:?}", channel_ids);
-	log_info!(logger, "Last sync timestamp: {}", last_sync_timestamp);
+	log_debug!(logger, "Channel IDs: {:?}", channel_ids);
+	log_debug!(logger, "Last sync timestamp: {}", last_sync_timestamp);
 	let last_sync_timestamp_float = last_sync_timestamp as f64;
 
-	log_info!(logger, "Obtaining corresponding database entries");
+	log_debug!(logger, "Obtaining corresponding database entries");
 	// get all the channel announcements that are currently in the network graph
-	let announcement_rows = client.query_raw("SELECT announcement_signed, CAST(EXTRACT('epoch' from seen) AS BIGINT) AS seen FROM channel_announcements WHERE short_channel_id = any($1) ORDER BY short_channel_id ASC", [&channel_ids]).await.unwrap();
+	let announcement_rows = client.query_raw("SELECT announcement_signed, CAST(EXTRACT('epoch' from seen) AS BIGINT) AS seen FROM channel_announcements WHERE short_channel_id = any($1) ORDER BY short_channel_id ASC", [&channel_ids]).await?;
 	let mut pinned_rows = Box::pin(announcement_rows);
 
 	let mut announcement_count = 0;
 	while let Some(row_res) = pinned_rows.next().await {
-		let current_announcement_row = row_res.unwrap();
+		let current_announcement_row = row_res?;
 		let blob: Vec<u8> = current_announcement_row.get("announcement_signed");
 		let mut readable = Cursor::new(blob);
 		let unsigned_announcement = ChannelAnnouncement::read(&mut readable).unwrap().contents;
@@ -115,7 +119,7 @@ pub(super) async fn fetch_channel_announcements<L: Deref>(delta_set: &mut DeltaS
 	{
 		// THIS STEP IS USED TO DETERMINE IF A CHANNEL SHOULD BE OMITTED FROM THE DELTA
 
-		log_info!(logger, "Annotating channel announcements whose oldest channel update in a given direction occurred after the last sync");
+		log_debug!(logger, "Annotating channel announcements whose oldest channel update in a given direction occurred after the last sync");
 		// Steps:
 		// — Obtain all updates, distinct by (scid, direction), ordered by seen DESC // to find the oldest update in a given direction
 		// — From those updates, select distinct by (scid), ordered by seen DESC (to obtain the newer one per direction)
@@ -137,12 +141,12 @@ pub(super) async fn fetch_channel_announcements<L: Deref>(delta_set: &mut DeltaS
 				ORDER BY short_channel_id ASC, seen DESC
 			) AS distinct_chans
 			WHERE distinct_chans.seen >= TO_TIMESTAMP($2)
-			", params).await.unwrap();
+			", params).await?;
 		let mut pinned_updates = Box::pin(newer_oldest_directional_updates);
 
 		let mut newer_oldest_directional_update_count = 0;
 		while let Some(row_res) = pinned_updates.next().await {
-			let current_row = row_res.unwrap();
+			let current_row = row_res?;
 
 			let scid: i64 = current_row.get("short_channel_id");
 			let current_seen_timestamp = current_row.get::<_, i64>("seen") as u32;
@@ -160,7 +164,7 @@ pub(super) async fn fetch_channel_announcements<L: Deref>(delta_set: &mut DeltaS
 	{
 		// THIS STEP IS USED TO DETERMINE IF A REMINDER UPDATE SHOULD BE SENT
 
-		log_info!(logger, "Annotating channel announcements whose latest channel update in a given direction occurred more than six days ago");
+		log_debug!(logger, "Annotating channel announcements whose latest channel update in a given direction occurred more than six days ago");
 		// Steps:
 		// — Obtain all updates, distinct by (scid, direction), ordered by seen DESC
 		// — From those updates, select distinct by (scid), ordered by seen ASC (to obtain the older one per direction)
@@ -180,12 +184,12 @@ pub(super) async fn fetch_channel_announcements<L: Deref>(delta_set: &mut DeltaS
 				ORDER BY short_channel_id ASC, seen ASC
 			) AS distinct_chans
 			WHERE distinct_chans.seen <= TO_TIMESTAMP($2)
-			", params).await.unwrap();
+			", params).await?;
 		let mut pinned_updates = Box::pin(older_latest_directional_updates);
 
 		let mut older_latest_directional_update_count = 0;
 		while let Some(row_res) = pinned_updates.next().await {
-			let current_row = row_res.unwrap();
+			let current_row = row_res?;
 			let scid: i64 = current_row.get("short_channel_id");
 
 			// annotate this channel as requiring that reminders be sent to the client
@@ -219,11 +223,16 @@ pub(super) async fn fetch_channel_announcements<L: Deref>(delta_set: &mut DeltaS
 		}
 		log_info!(logger, "Fetched {} update rows of the latest update in the less recently updated direction", older_latest_directional_update_count);
 	}
+
+	Ok(())
 }
 
-pub(super) async fn fetch_channel_updates<L: Deref>(delta_set: &mut DeltaSet, client: &Client, last_sync_timestamp: u32, logger: L) where L::Target: Logger {
+/// Fetch the channel updates seen after `last_sync_timestamp`, up to and including `max_seen`,
+/// along with the latest update before them in each direction
+pub(super) async fn fetch_channel_updates<L: Deref>(delta_set: &mut DeltaSet, client: &Client, last_sync_timestamp: u32, max_seen: u32, logger: L) -> Result<(), tokio_postgres::Error> where L::Target: Logger {
 	let start = Instant::now();
 	let last_sync_timestamp_float = last_sync_timestamp as f64;
+	let max_seen_float = max_seen as f64;
 
 	// get the latest channel update in each direction prior to last_sync_timestamp, provided
 	// there was an update in either direction that happened after the last sync (to avoid
@@ -236,11 +245,11 @@ pub(super) async fn fetch_channel_updates<L: Deref>(delta_set: &mut DeltaSet, cl
 			WHERE seen < TO_TIMESTAMP($1) AND short_channel_id IN (
 				SELECT DISTINCT ON (short_channel_id) short_channel_id
 				FROM channel_updates
-				WHERE seen >= TO_TIMESTAMP($1)
+				WHERE seen >= TO_TIMESTAMP($1) AND seen <= TO_TIMESTAMP($2)
 			)
 			ORDER BY short_channel_id ASC, direction ASC, seen DESC
 		)
-		", [last_sync_timestamp_float]).await.unwrap();
+		", [last_sync_timestamp_float, max_seen_float]).await?;
 	let mut pinned_rows = Box::pin(reference_rows);
 
 	log_info!(logger, "Fetched reference rows in {:?}", start.elapsed());
@@ -250,7 +259,7 @@ pub(super) async fn fetch_channel_updates<L: Deref>(delta_set: &mut DeltaSet, cl
 	let mut reference_row_count = 0;
 
 	while let Some(row_res) = pinned_rows.next().await {
-		let current_reference = row_res.unwrap();
+		let current_reference = row_res?;
 		let update_id: i32 = current_reference.get("id");
 		last_seen_update_ids.push(update_id);
 		non_intermediate_ids.insert(update_id);
@@ -287,9 +296,9 @@ pub(super) async fn fetch_channel_updates<L: Deref>(delta_set: &mut DeltaSet, cl
 	let intermediate_updates = client.query_raw("
 		SELECT id, direction, blob_signed, CAST(EXTRACT('epoch' from seen) AS BIGINT) AS seen
 		FROM channel_updates
-		WHERE seen >= TO_TIMESTAMP($1)
+		WHERE seen >= TO_TIMESTAMP($1) AND seen <= TO_TIMESTAMP($2)
 		ORDER BY short_channel_id ASC, timestamp DESC
-		", [last_sync_timestamp_float]).await.unwrap();
+		", [last_sync_timestamp_float, max_seen_float]).await?;
 	let mut pinned_updates = Box::pin(intermediate_updates);
 	log_info!(logger, "Fetched intermediate rows in {:?}", start.elapsed());
 
@@ -299,7 +308,7 @@ pub(super) async fn fetch_channel_updates<L: Deref>(delta_set: &mut DeltaSet, cl
 	// let mut previously_seen_directions = (false, false);
 	let mut intermediate_update_count = 0;
 	while let Some(row_res) = pinned_updates.next().await {
-		let intermediate_update = row_res.unwrap();
+		let intermediate_update = row_res?;
 		let update_id: i32 = intermediate_update.get("id");
 		if non_intermediate_ids.contains(&update_id) {
 			continue;
@@ -366,6 +375,8 @@ pub(super) async fn fetch_channel_updates<L: Deref>(delta_set: &mut DeltaSet, cl
 		}
 	}
 	log_info!(logger, "Processed intermediate rows ({}) (delta size: {}): {:?}", intermediate_update_count, delta_set.len(), start.elapsed());
+
+	Ok(())
 }
 
 pub(super) fn filter_delta_set<L: Deref>(delta_set: &mut DeltaSet, logger: L) where L::Target: Logger {
@@ -400,6 +411,141 @@ pub(super) fn filter_delta_set<L: Deref>(delta_set: &mut DeltaSet, logger: L) wh
 
 	let new_length = delta_set.len();
 	if original_length != new_length {
-		log_info!(logger, "length modified!");
+		log_debug!(logger, "length modified!");
+	}
+}
+
+/// Remove all channels that aren't currently enabled in either direction, according to the
+/// network graph.
+///
+/// In a delta, channels with an update since `last_sync_timestamp` are kept regardless, because
+/// clients that already know them would otherwise never learn that they were disabled. Once
+/// re-enabled, a channel is included again, but clients that skipped its announcement while it
+/// was excluded will only learn about it from a delta that includes the announcement, such as a
+/// full sync.
+pub(super) fn filter_disabled_channels<L: Deref>(delta_set: &mut DeltaSet, network_graph: &NetworkGraph<L>, last_sync_timestamp: u32, logger: L) where L::Target: Logger {
+	let original_length = delta_set.len();
+	{
+		let read_only_graph = network_graph.read_only();
+		let is_enabled = |direction: &Option<ChannelUpdateInfo>| {
+			direction.as_ref().map_or(false, |info| info.enabled)
+		};
+		let has_recent_update = |direction: &Option<DirectedUpdateDelta>| {
+			direction.as_ref().map_or(false, |direction| direction.latest_update_after_seen.is_some())
+		};
+		delta_set.retain(|scid, channel_delta| {
+			if last_sync_timestamp > 0 && (has_recent_update(&channel_delta.updates.0) || has_recent_update(&channel_delta.updates.1)) {
+				return true;
+			}
+			if let Some(channel) = read_only_graph.channel(*scid) {
+				is_enabled(&channel.one_to_two) || is_enabled(&channel.two_to_one)
+			} else {
+				false
+			}
+		});
+	}
+	log_info!(logger, "Excluded {} disabled channels", original_length - delta_set.len());
+}
+
+/// Only retain channels for which the network graph knows an update in both directions
+pub(super) fn filter_unidirectional_channels<L: Deref>(delta_set: &mut DeltaSet, network_graph: &NetworkGraph<L>, logger: L) where L::Target: Logger {
+	let original_length = delta_set.len();
+	{
+		let read_only_graph = network_graph.read_only();
+		delta_set.retain(|scid, _| {
+			read_only_graph.channel(*scid).map_or(false, |channel| channel.one_to_two.is_some() && channel.two_to_one.is_some())
+		});
+	}
+	log_info!(logger, "Excluded {} channels missing an update in either direction", original_length - delta_set.len());
+}
+
+/// Only retain channels confirmed after the given block height, which is encoded in the top
+/// three bytes of the short channel ID.
+pub(super) fn filter_by_block_height<L: Deref>(delta_set: &mut DeltaSet, last_sync_block_height: u32, logger: L) where L::Target: Logger {
+	let original_length = delta_set.len();
+	delta_set.retain(|scid, _| (scid >> 40) as u32 > last_sync_block_height);
+	log_info!(logger, "Excluded {} channels confirmed at or before block {}", original_length - delta_set.len(), last_sync_block_height);
+}
+
+/// Only retain channels that have seen an update in either direction at or after
+/// `min_last_update`, according to the network graph.
+pub(super) fn filter_stale_channels<L: Deref>(delta_set: &mut DeltaSet, network_graph: &NetworkGraph<L>, min_last_update: u32, logger: L) where L::Target: Logger {
+	let original_length = delta_set.len();
+	{
+		let read_only_graph = network_graph.read_only();
+		let is_recent = |direction: &Option<ChannelUpdateInfo>| {
+			direction.as_ref().map_or(false, |info| info.last_update >= min_last_update)
+		};
+		delta_set.retain(|scid, _| {
+			if let Some(channel) = read_only_graph.channel(*scid) {
+				is_recent(&channel.one_to_two) || is_recent(&channel.two_to_one)
+			} else {
+				false
+			}
+		});
+	}
+	log_info!(logger, "Excluded {} channels without updates since {}", original_length - delta_set.len(), min_last_update);
+}
+
+/// Remove the channels announced after `max_seen`, and any reminders, so that the delta set only
+/// reflects the gossip seen up to then. Updates after `max_seen` aren't fetched in the first place.
+pub(super) fn filter_seen_after<L: Deref>(delta_set: &mut DeltaSet, max_seen: u32, logger: L) where L::Target: Logger {
+	let original_length = delta_set.len();
+	delta_set.retain(|_, channel_delta| channel_delta.announcement.as_ref().map_or(false, |announcement| announcement.seen <= max_seen));
+	for channel_delta in delta_set.values_mut() {
+		if channel_delta.first_bidirectional_updates_seen.map_or(false, |seen| seen > max_seen) {
+			channel_delta.first_bidirectional_updates_seen = None;
+		}
+		// reminders are due relative to the present, not to the end of the window
+		channel_delta.requires_reminder = false;
+		for directed_updates in [&mut channel_delta.updates.0, &mut channel_delta.updates.1].into_iter().flatten() {
+			directed_updates.serialization_update_flags = None;
+		}
+	}
+	log_info!(logger, "Excluded {} channels announced after {}", original_length - delta_set.len(), max_seen);
+}
+
+/// Drop blocked channels, as well as all channels of blocked nodes. A channel's nodes are looked
+/// up in the network graph, because its announcement is only part of the delta if it's new.
+pub(super) fn filter_blocklisted<L: Deref>(delta_set: &mut DeltaSet, network_graph: &NetworkGraph<L>, blocklist: &Blocklist, logger: L) where L::Target: Logger {
+	let original_length = delta_set.len();
+	{
+		let read_only_graph = network_graph.read_only();
+		delta_set.retain(|scid, delta| {
+			if blocklist.short_channel_ids.contains(scid) {
+				return false;
+			}
+			let node_ids = if let Some(channel) = read_only_graph.channel(*scid) {
+				Some((channel.node_one, channel.node_two))
+			} else {
+				delta.announcement.as_ref().map(|delta| (delta.announcement.node_id_1, delta.announcement.node_id_2))
+			};
+			match node_ids {
+				Some((node_id_1, node_id_2)) => !blocklist.node_ids.contains(&node_id_1) && !blocklist.node_ids.contains(&node_id_2),
+				None => true,
+			}
+		});
+	}
+	log_info!(logger, "Excluded {} blocklisted channels", original_length - delta_set.len());
+}
+
+/// Retain only the channels with at least one endpoint among `node_ids`, for snapshots of a
+/// subgraph
+pub(super) fn filter_by_nodes<L: Deref>(delta_set: &mut DeltaSet, network_graph: &NetworkGraph<L>, node_ids: &HashSet<NodeId>, logger: L) where L::Target: Logger {
+	let original_length = delta_set.len();
+	{
+		let read_only_graph = network_graph.read_only();
+		delta_set.retain(|scid, delta| {
+			let endpoints = if let Some(channel) = read_only_graph.channel(*scid) {
+				Some((channel.node_one, channel.node_two))
+			} else {
+				delta.announcement.as_ref().map(|delta| (delta.announcement.node_id_1, delta.announcement.node_id_2))
+			};
+			match endpoints {
+				Some((node_id_1, node_id_2)) => node_ids.contains(&node_id_1) || node_ids.contains(&node_id_2),
+				None => false,
+			}
+		});
 	}
+	log_info!(logger, "Excluded {} channels outside of the requested subgraph", original_length - delta_set.len());
 }
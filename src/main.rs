@@ -1,9 +1,112 @@
 use std::sync::Arc;
-use rapid_gossip_sync_server::RapidSyncProcessor;
+use rapid_gossip_sync_server::{build_runtime, rebuild_finalized_symlinks, snapshot_to_json, validate_config, verify_finalized_snapshots, RapidSyncProcessor};
 use rapid_gossip_sync_server::types::RGSSLogger;
 
-#[tokio::main]
-async fn main() {
+fn main() {
+	if let Err(error) = validate_config() {
+		eprintln!("{}", error);
+		std::process::exit(1);
+	}
+	let runtime = build_runtime().expect("Failed to build runtime");
+	runtime.block_on(run());
+}
+
+async fn run() {
 	let logger = Arc::new(RGSSLogger::new());
-	RapidSyncProcessor::new(logger).start_sync().await;
+	let arguments: Vec<String> = std::env::args().skip(1).collect();
+
+	// `export-json <snapshot path> [--force]` prints a snapshot file as JSON for debugging
+	if arguments.first().map(String::as_str) == Some("export-json") {
+		let force = arguments.iter().any(|argument| argument == "--force");
+		let path = match arguments.iter().skip(1).find(|argument| !argument.starts_with("--")) {
+			Some(path) => path,
+			None => {
+				eprintln!("Usage: export-json <snapshot path> [--force]");
+				std::process::exit(2);
+			}
+		};
+		let data = std::fs::read(path).expect("Failed to read snapshot file");
+		match snapshot_to_json(&data, force) {
+			Ok(json) => println!("{}", serde_json::to_string_pretty(&json).unwrap()),
+			Err(error) => {
+				eprintln!("{}", error);
+				std::process::exit(1);
+			}
+		}
+		return;
+	}
+
+	// `verify` checks the finalized snapshot tree, exiting with a nonzero status if it's broken
+	if arguments.first().map(String::as_str) == Some("verify") {
+		let problems = verify_finalized_snapshots();
+		for problem in &problems {
+			println!("{}", problem);
+		}
+		if !problems.is_empty() {
+			std::process::exit(1);
+		}
+		println!("Snapshot tree is intact");
+		return;
+	}
+
+	// `rebuild-symlinks` recreates the symlink tree from the finalized snapshot files
+	if arguments.first().map(String::as_str) == Some("rebuild-symlinks") {
+		match rebuild_finalized_symlinks(logger) {
+			Ok(count) => println!("Rebuilt {} symlinks", count),
+			Err(error) => {
+				eprintln!("Failed to rebuild symlinks: {}", error);
+				std::process::exit(1);
+			}
+		}
+		return;
+	}
+
+	let processor = RapidSyncProcessor::new(logger);
+
+	// `reupload` uploads the current set of finalized snapshots and exits
+	if arguments.first().map(String::as_str) == Some("reupload") {
+		match processor.reupload_finalized_snapshots() {
+			Ok(count) => println!("Re-uploaded {} snapshots", count),
+			Err(error) => {
+				eprintln!("Failed to re-upload snapshots: {}", error);
+				std::process::exit(1);
+			}
+		}
+		return;
+	}
+
+	// `bench [--json]` times the serialization of every scope from the current graph and exits
+	if arguments.first().map(String::as_str) == Some("bench") {
+		let benchmarks = match processor.benchmark_serialization().await {
+			Ok(benchmarks) => benchmarks,
+			Err(error) => {
+				eprintln!("{}", error);
+				std::process::exit(1);
+			}
+		};
+		if arguments.iter().any(|argument| argument == "--json") {
+			println!("{}", serde_json::to_string_pretty(&benchmarks).unwrap());
+			return;
+		}
+		println!("{:>10}  {:>10}  {:>10}  {:>10}  {:>8}  {:>13}  {:>7}  {:>12}", "scope", "last sync", "seconds", "bytes", "messages", "announcements", "updates", "messages/s");
+		for benchmark in benchmarks {
+			let scope = if benchmark.scope == u64::MAX { "full".to_string() } else { format!("{}s", benchmark.scope) };
+			let throughput = benchmark.message_count as f64 / benchmark.duration_secs.max(f64::EPSILON);
+			println!("{:>10}  {:>10}  {:>10.3}  {:>10}  {:>8}  {:>13}  {:>7}  {:>12.0}", scope, benchmark.last_sync_timestamp, benchmark.duration_secs, benchmark.size_bytes, benchmark.message_count, benchmark.announcement_count, benchmark.update_count, throughput);
+		}
+		return;
+	}
+
+	// `--once` generates a single set of snapshots and exits, e.g. for running from cron
+	if arguments.iter().any(|argument| argument == "--once") {
+		if processor.run_once().await.is_none() {
+			std::process::exit(1);
+		}
+		return;
+	}
+
+	if let Err(error) = processor.start_sync().await {
+		eprintln!("{}", error);
+		std::process::exit(1);
+	}
 }
@@ -1,12 +1,15 @@
 use std::cmp::max;
 use std::collections::HashMap;
+use std::io::{Cursor, Read};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use bitcoin::BlockHash;
 use bitcoin::hashes::Hash;
-use lightning::ln::msgs::{UnsignedChannelAnnouncement, UnsignedChannelUpdate};
-use lightning::util::ser::{BigSize, Writeable};
-use crate::config;
+use lightning::ln::features::ChannelFeatures;
+use lightning::ln::msgs::{DecodeError, UnsignedChannelAnnouncement, UnsignedChannelUpdate};
+use lightning::routing::gossip::NodeId;
+use lightning::util::ser::{BigSize, Readable, Writeable};
+use crate::{config, SerializedResponse};
 
 use crate::lookup::{DeltaSet, DirectedUpdateDelta};
 
@@ -319,3 +322,151 @@ pub(super) fn find_most_common_histogram_entry_with_default<T: Copy>(histogram:
 	// though for htlc maximum msat it could be a u64::max
 	default
 }
+
+/// Recover the counts of a serialized snapshot by decoding its announcements and updates, so that
+/// snapshot files can be turned back into a [`SerializedResponse`].
+pub(super) fn read_serialized_response(data: Vec<u8>) -> Result<SerializedResponse, DecodeError> {
+	let snapshot = decode_snapshot(&data)?;
+	let announcement_count = snapshot.announcements.len() as u32;
+	let update_count_incremental = snapshot.updates.iter().filter(|update| update.incremental).count() as u32;
+	let update_count_full = snapshot.updates.len() as u32 - update_count_incremental;
+	let update_count = update_count_full + update_count_incremental;
+	Ok(SerializedResponse {
+		content_hash: crate::content_hash(&data),
+		data,
+		message_count: announcement_count + update_count,
+		announcement_count,
+		update_count,
+		update_count_full,
+		update_count_incremental,
+		last_sync_block_height: None,
+	})
+}
+
+/// The short channel IDs of all channels a serialized snapshot announces or updates, in ascending
+/// order and without duplicates
+pub(super) fn read_short_channel_ids(data: &[u8]) -> Result<Vec<u64>, DecodeError> {
+	let snapshot = decode_snapshot(data)?;
+	let mut short_channel_ids: Vec<u64> = snapshot.announcements.iter().map(|announcement| announcement.short_channel_id)
+		.chain(snapshot.updates.iter().map(|update| update.short_channel_id))
+		.collect();
+	short_channel_ids.sort_unstable();
+	short_channel_ids.dedup();
+	Ok(short_channel_ids)
+}
+
+/// The contents of a serialized snapshot, as written rather than as applied by a client
+pub(super) struct DecodedSnapshot {
+	pub(super) chain_hash: BlockHash,
+	pub(super) latest_seen_timestamp: u32,
+	pub(super) announcements: Vec<DecodedAnnouncement>,
+	/// Only present if the snapshot contains any updates
+	pub(super) default_values: Option<DefaultUpdateValues>,
+	pub(super) updates: Vec<DecodedUpdate>,
+}
+
+pub(super) struct DecodedAnnouncement {
+	pub(super) short_channel_id: u64,
+	pub(super) features: ChannelFeatures,
+	pub(super) node_id_1: NodeId,
+	pub(super) node_id_2: NodeId,
+}
+
+/// A channel update as serialized. Properties that were not written are `None`: full updates
+/// take them from the snapshot's default values, and incremental ones leave them unchanged.
+pub(super) struct DecodedUpdate {
+	pub(super) short_channel_id: u64,
+	pub(super) incremental: bool,
+	/// The channel flags, i. e. the direction and disabled bits
+	pub(super) channel_flags: u8,
+	pub(super) cltv_expiry_delta: Option<u16>,
+	pub(super) htlc_minimum_msat: Option<u64>,
+	pub(super) fee_base_msat: Option<u32>,
+	pub(super) fee_proportional_millionths: Option<u32>,
+	pub(super) htlc_maximum_msat: Option<u64>,
+}
+
+/// Decode a serialized snapshot into its announcements and updates, rejecting trailing data
+pub(super) fn decode_snapshot(data: &[u8]) -> Result<DecodedSnapshot, DecodeError> {
+	let mut reader = Cursor::new(data);
+	let mut prefix = [0u8; 4];
+	reader.read_exact(&mut prefix).map_err(|_| DecodeError::ShortRead)?;
+	if prefix != crate::GOSSIP_PREFIX {
+		return Err(DecodeError::UnknownVersion);
+	}
+	let chain_hash: BlockHash = Readable::read(&mut reader)?;
+	let latest_seen_timestamp: u32 = Readable::read(&mut reader)?;
+	let node_id_count: u32 = Readable::read(&mut reader)?;
+	let mut node_ids = Vec::with_capacity(node_id_count.min(1 << 16) as usize);
+	for _ in 0..node_id_count {
+		let node_id: NodeId = Readable::read(&mut reader)?;
+		node_ids.push(node_id);
+	}
+	let node_id_at = |index: BigSize| node_ids.get(index.0 as usize).copied().ok_or(DecodeError::InvalidValue);
+
+	let announcement_count: u32 = Readable::read(&mut reader)?;
+	let mut announcements = Vec::with_capacity(announcement_count.min(1 << 16) as usize);
+	let mut short_channel_id = 0;
+	for _ in 0..announcement_count {
+		let features: ChannelFeatures = Readable::read(&mut reader)?;
+		let scid_delta: BigSize = Readable::read(&mut reader)?;
+		short_channel_id = checked_scid(short_channel_id, scid_delta)?;
+		let node_id_1 = node_id_at(Readable::read(&mut reader)?)?;
+		let node_id_2 = node_id_at(Readable::read(&mut reader)?)?;
+		announcements.push(DecodedAnnouncement { short_channel_id, features, node_id_1, node_id_2 });
+	}
+
+	let update_count: u32 = Readable::read(&mut reader)?;
+	let default_values = if update_count > 0 {
+		Some(DefaultUpdateValues {
+			cltv_expiry_delta: Readable::read(&mut reader)?,
+			htlc_minimum_msat: Readable::read(&mut reader)?,
+			fee_base_msat: Readable::read(&mut reader)?,
+			fee_proportional_millionths: Readable::read(&mut reader)?,
+			htlc_maximum_msat: Readable::read(&mut reader)?,
+		})
+	} else {
+		None
+	};
+	let mut updates = Vec::with_capacity(update_count.min(1 << 16) as usize);
+	let mut short_channel_id = 0;
+	for _ in 0..update_count {
+		let scid_delta: BigSize = Readable::read(&mut reader)?;
+		short_channel_id = checked_scid(short_channel_id, scid_delta)?;
+		let flags: u8 = Readable::read(&mut reader)?;
+		let mut update = DecodedUpdate {
+			short_channel_id,
+			incremental: flags & 0b_1000_0000 != 0,
+			channel_flags: flags & 0b_0000_0011,
+			cltv_expiry_delta: None,
+			htlc_minimum_msat: None,
+			fee_base_msat: None,
+			fee_proportional_millionths: None,
+			htlc_maximum_msat: None,
+		};
+		if flags & 0b_0100_0000 != 0 {
+			update.cltv_expiry_delta = Some(Readable::read(&mut reader)?);
+		}
+		if flags & 0b_0010_0000 != 0 {
+			update.htlc_minimum_msat = Some(Readable::read(&mut reader)?);
+		}
+		if flags & 0b_0001_0000 != 0 {
+			update.fee_base_msat = Some(Readable::read(&mut reader)?);
+		}
+		if flags & 0b_0000_1000 != 0 {
+			update.fee_proportional_millionths = Some(Readable::read(&mut reader)?);
+		}
+		if flags & 0b_0000_0100 != 0 {
+			update.htlc_maximum_msat = Some(Readable::read(&mut reader)?);
+		}
+		updates.push(update);
+	}
+	if reader.position() as usize != data.len() {
+		return Err(DecodeError::InvalidValue);
+	}
+	Ok(DecodedSnapshot { chain_hash, latest_seen_timestamp, announcements, default_values, updates })
+}
+
+fn checked_scid(previous_scid: u64, scid_delta: BigSize) -> Result<u64, DecodeError> {
+	previous_scid.checked_add(scid_delta.0).ok_or(DecodeError::InvalidValue)
+}
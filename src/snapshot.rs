@@ -1,78 +1,924 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
-use std::fs;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
 use std::ops::Deref;
 use std::os::unix::fs::symlink;
-use std::sync::Arc;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use lightning::{log_info, log_error};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use fs2::FileExt;
+use lightning::{log_debug, log_info, log_error, log_warn};
 
 use lightning::routing::gossip::NetworkGraph;
 use lightning::util::logger::Logger;
+use lightning_rapid_gossip_sync::RapidGossipSync;
+use tokio::sync::broadcast;
 
+use crate::client::UploadCredentials;
+use crate::compression::SnapshotCompression;
 use crate::config;
-use crate::config::cache_path;
+use crate::{SerializationConfig, SerializedResponse};
+use crate::hashing::HashAlgorithm;
+use crate::socket::SnapshotSocket;
+
+/// The name of the file a snapshot generated at `reference_timestamp` for a given `scope` is
+/// persisted under, where `last_sync_timestamp` is the timestamp the snapshot is a delta from.
+pub fn snapshot_filename(reference_timestamp: u64, scope: u64, last_sync_timestamp: u64) -> String {
+	format!("snapshot__calculated-at:{}__range:{}-scope__previous-sync:{}.lngossip", reference_timestamp, scope, last_sync_timestamp)
+}
+
+/// The inverse of [`snapshot_filename`], returning the reference timestamp, scope, and last sync
+/// timestamp a snapshot file name was generated from.
+pub(crate) fn parse_snapshot_filename(filename: &str) -> Option<(u64, u64, u64)> {
+	let components = filename.strip_prefix("snapshot__calculated-at:")?.strip_suffix(".lngossip")?;
+	let (reference_timestamp, components) = components.split_once("__range:")?;
+	let (scope, last_sync_timestamp) = components.split_once("-scope__previous-sync:")?;
+	Some((reference_timestamp.parse().ok()?, scope.parse().ok()?, last_sync_timestamp.parse().ok()?))
+}
+
+/// The name of a snapshot file derived from the configured hash of its (uncompressed) contents,
+/// used instead of [`snapshot_filename`] if content-addressed filenames are enabled. Identical snapshots thus
+/// always share a name, which allows them to be cached indefinitely, leaving the symlinks as the
+/// only mutable part of the tree.
+///
+/// The name starts with the algorithm, e.g. `snapshot-sha256-<hash prefix>.lngossip`, so that
+/// clients can tell which algorithm to verify the contents with.
+pub fn content_addressed_filename(data: &[u8], algorithm: HashAlgorithm) -> String {
+	let hash = algorithm.hex_digest(data);
+	format!("snapshot-{}-{}.lngossip", algorithm.name(), &hash[..32])
+}
+
+/// The scopes snapshots are generated for, in ascending order, with the full sync (`u64::MAX`)
+/// being the last one.
+pub fn snapshot_scopes(snapshot_interval: u64) -> Vec<u64> {
+	let mut snapshot_scopes = vec![];
+	{ // double the coefficient until it reaches the maximum (limited) snapshot scope
+		let mut current_scope = snapshot_interval;
+		loop {
+			snapshot_scopes.push(current_scope);
+			if current_scope >= config::MAX_SNAPSHOT_SCOPE as u64 {
+				snapshot_scopes.push(u64::MAX);
+				break;
+			}
+
+			// double the current factor
+			current_scope <<= 1;
+		}
+	}
+	snapshot_scopes
+}
+
+/// Snapshot scopes of one to `daily_count` days, followed by the `weekly_count` multiples of a week
+/// that exceed the largest of them, and the full sync. E. g. 6 daily and 3 weekly scopes are
+/// 1 through 6 days, then 7, 14, and 21 days.
+pub fn calendar_snapshot_scopes(daily_count: u64, weekly_count: u64) -> Vec<u64> {
+	const DAY: u64 = 24 * 3600;
+	const WEEK: u64 = 7 * DAY;
+	let mut snapshot_scopes: Vec<u64> = (1..=daily_count).map(|days| days * DAY).collect();
+	let first_week = daily_count * DAY / WEEK + 1;
+	snapshot_scopes.extend((first_week..first_week + weekly_count).map(|weeks| weeks * WEEK));
+	snapshot_scopes.push(u64::MAX);
+	snapshot_scopes
+}
+
+/// The timestamp a snapshot of the given scope is a delta from.
+///
+/// Scopes reaching back past the epoch, most notably the full sync's `u64::MAX`, clamp to 0
+/// rather than overflowing.
+pub fn scope_last_sync_timestamp(reference_timestamp: u64, scope: u64) -> u64 {
+	reference_timestamp.saturating_sub(scope)
+}
+
+/// The timestamp of the symlink `symlink_index` granularity intervals before the reference
+/// timestamp, clamping to 0 rather than overflowing for indices reaching back past the epoch.
+pub(crate) fn symlink_last_sync_timestamp(reference_timestamp: u64, granularity_interval: u64, symlink_index: u64) -> u64 {
+	reference_timestamp.saturating_sub(granularity_interval.saturating_mul(symlink_index))
+}
+
+/// The `(scope, last_sync_timestamp)` pairs of the snapshots generated at `reference_timestamp`,
+/// where each snapshot is a delta from its last sync timestamp. Scopes reaching back further than
+/// the epoch, such as the full sync, are deltas from timestamp 0.
+pub fn snapshot_sync_timestamps(reference_timestamp: u64, snapshot_scopes: &[u64]) -> Vec<(u64, u64)> {
+	snapshot_scopes.iter().map(|scope| {
+		(*scope, scope_last_sync_timestamp(reference_timestamp, *scope))
+	}).collect()
+}
+
+/// The scope the symlink for the `symlink_index`-th granularity interval before the reference
+/// timestamp points at. Index 0 is the symlink for timestamp 0, i. e. clients without any prior
+/// sync, which is served `initial_sync_scope`.
+///
+/// `snapshot_scopes` must be sorted ascendingly. Unless they end with `u64::MAX`, symlinks older
+/// than the largest scope reference no scope, and `None` is returned.
+pub(crate) fn referenced_scope(symlink_index: u64, granularity_interval: u64, snapshot_scopes: &[u64], initial_sync_scope: u64) -> Option<u64> {
+	if symlink_index == 0 {
+		return Some(initial_sync_scope);
+	}
+
+	/*
+	We have snapshots for 6-day- and 7-day-intervals, but the next interval is
+	14 days. So if somebody requests an update with a timestamp that is 10 days old,
+	there is no longer a snapshot for that specific interval.
+
+	The correct snapshot will be the next highest interval, i. e. for 14 days.
+
+	The `snapshot_sync_day_factors` array is sorted ascendingly, so find() will
+	return on the first iteration that is at least equal to the requested interval.
+
+	Note, however, that the last value in the array is u64::max, which means that
+	multiplying it with snapshot_interval will overflow. To avoid that, we use
+	saturating_mul.
+	 */
+
+	// find min(x) in snapshot_scopes where i * granularity <= x (the current scope)
+	snapshot_scopes.iter().find(|current_scope| {
+		symlink_index.saturating_mul(granularity_interval) <= **current_scope
+	}).copied()
+}
+
+/// Determine which snapshot a client whose last sync was at `last_sync_timestamp` is served
+/// from the set generated at `reference_timestamp`, returning its scope and filename.
+///
+/// If content-addressed filenames are enabled, the returned filename is the one the snapshot
+/// would have without them, as the actual one depends on its contents.
+///
+/// This mirrors the symlink layout of the snapshotter without touching the disk. It returns `None`
+/// for timestamps that never have a symlink: those that are not a multiple of
+/// `granularity_interval` before the reference timestamp, that lie in the future, or that no
+/// scope reaches back to. The reference timestamp itself is linked to an empty snapshot unless
+/// that is disabled, in which case requests for it are left to the front-end.
+///
+/// It does not know which symlinks a cycle skipped, so the returned snapshot may still lack a
+/// symlink for timestamps before the anchor timestamp, beyond a maximum symlink count, or left
+/// out once the symlink time budget ran out.
+/// `snapshot_scopes` must be sorted ascendingly, as generated.
+pub fn resolve_snapshot(last_sync_timestamp: u64, reference_timestamp: u64, granularity_interval: u64, snapshot_scopes: &[u64], initial_sync_scope: u64) -> Option<(u64, String)> {
+	let symlink_index = if last_sync_timestamp == 0 {
+		0
+	} else {
+		if last_sync_timestamp >= reference_timestamp {
+			return None;
+		}
+		let age = reference_timestamp - last_sync_timestamp;
+		if age % granularity_interval != 0 {
+			return None;
+		}
+		age / granularity_interval
+	};
+	let scope = referenced_scope(symlink_index, granularity_interval, snapshot_scopes, initial_sync_scope)?;
+	let filename = snapshot_filename(reference_timestamp, scope, scope_last_sync_timestamp(reference_timestamp, scope));
+	Some((scope, filename))
+}
+
+/// The `(symlink_name, target_filename)` pairs of the symlinks generated at `reference_timestamp`,
+/// in order of increasing age, i. e. starting with the symlink for timestamp 0, followed by one
+/// for every granularity interval before the reference timestamp, back to `anchor_timestamp` or
+/// until `max_symlink_count` is reached. The symlink for the reference timestamp itself, which
+/// leads to the empty snapshot, is not included.
+///
+/// Like [`resolve_snapshot`], this returns the filenames the snapshots have without
+/// content-addressed filenames.
+pub fn symlink_plan(reference_timestamp: u64, granularity_interval: u64, anchor_timestamp: u64, snapshot_scopes: &[u64], initial_sync_scope: u64, max_symlink_count: Option<u64>) -> Vec<(String, String)> {
+	plan_symlinks(reference_timestamp, granularity_interval, anchor_timestamp, snapshot_scopes, initial_sync_scope, max_symlink_count, |scope| {
+		snapshot_filename(reference_timestamp, scope, scope_last_sync_timestamp(reference_timestamp, scope))
+	})
+}
+
+/// [`symlink_plan`], with the filename of each scope's snapshot given by `target_filename`
+fn plan_symlinks<F: FnMut(u64) -> String>(reference_timestamp: u64, granularity_interval: u64, anchor_timestamp: u64, snapshot_scopes: &[u64], initial_sync_scope: u64, max_symlink_count: Option<u64>, mut target_filename: F) -> Vec<(String, String)> {
+	// number of intervals since the anchor
+	let mut symlink_count = reference_timestamp.saturating_sub(anchor_timestamp) / granularity_interval;
+	if let Some(max_symlink_count) = max_symlink_count {
+		// this is primarily useful for testing
+		symlink_count = std::cmp::min(symlink_count, max_symlink_count);
+	};
+
+	(0..symlink_count).filter_map(|i| {
+		// first, determine which snapshot range should be referenced, if any
+		let referenced_scope = referenced_scope(i, granularity_interval, snapshot_scopes, initial_sync_scope)?;
+		let canonical_last_sync_timestamp = if i == 0 {
+			// special-case 0 to always refer to a full/initial sync
+			0
+		} else {
+			symlink_last_sync_timestamp(reference_timestamp, granularity_interval, i)
+		};
+		Some((format!("{}.bin", canonical_last_sync_timestamp), target_filename(referenced_scope)))
+	}).collect()
+}
+
+/// How often each of the operations finalizing a cycle's directories is attempted
+const FINALIZE_ATTEMPTS: u32 = 5;
+/// The delay before the first retry of a finalize operation, doubling with every further retry
+const FINALIZE_INITIAL_BACKOFF: Duration = Duration::from_millis(200);
+
+fn remove_dir_if_exists(path: &str) -> std::io::Result<()> {
+	if fs::metadata(path).is_ok() {
+		fs::remove_dir_all(path)?;
+	}
+	Ok(())
+}
+
+fn rename_if_exists(from: &str, to: &str) -> std::io::Result<()> {
+	if fs::metadata(from).is_ok() {
+		fs::rename(from, to)?;
+	}
+	Ok(())
+}
+
+/// The scopes to generate snapshots for, which are either the ones configured explicitly, the
+/// [`calendar_snapshot_scopes`] if daily or weekly counts are configured, or else the default
+/// [`snapshot_scopes`]. Either way, the full sync is always included.
+pub(crate) fn configured_snapshot_scopes(snapshot_interval: u64) -> Vec<u64> {
+	match config::snapshot_scopes_override() {
+		Some(mut scopes) => {
+			scopes.retain(|scope| *scope != u64::MAX);
+			scopes.sort_unstable();
+			scopes.dedup();
+			scopes.push(u64::MAX);
+			scopes
+		}
+		None => match (config::daily_snapshot_count(), config::weekly_snapshot_count()) {
+			(None, None) => snapshot_scopes(snapshot_interval),
+			(daily_count, weekly_count) => calendar_snapshot_scopes(daily_count.unwrap_or(6), weekly_count.unwrap_or(3)),
+		},
+	}
+}
+
+/// An inconsistency found in a finalized snapshot tree by [`verify_served_tree`]
+#[derive(Debug, PartialEq)]
+pub enum TreeProblem {
+	MissingDirectory(String),
+	/// A symlink whose target does not exist
+	DanglingSymlink { symlink: String, target: String },
+	/// A symlink pointing at a different snapshot than the one its timestamp is served
+	MisdirectedSymlink { symlink: String, target: String, expected: String },
+	MissingUpdateTime,
+	/// The update time, which is older than the maximum age
+	StaleUpdateTime(u64),
+	/// There is no symlink for the reference timestamp itself
+	MissingDummy,
+	/// The reference timestamp's symlink doesn't lead to an empty snapshot
+	InvalidDummy(String),
+}
+
+impl std::fmt::Display for TreeProblem {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			TreeProblem::MissingDirectory(path) => write!(f, "Directory {} is missing", path),
+			TreeProblem::DanglingSymlink { symlink, target } => write!(f, "Symlink {} points at missing file {}", symlink, target),
+			TreeProblem::MisdirectedSymlink { symlink, target, expected } => write!(f, "Symlink {} points at {} instead of {}", symlink, target, expected),
+			TreeProblem::MissingUpdateTime => write!(f, "update_time.txt is missing or malformed"),
+			TreeProblem::StaleUpdateTime(update_time) => write!(f, "Snapshots were last updated at {}", update_time),
+			TreeProblem::MissingDummy => write!(f, "No empty snapshot is linked for the reference timestamp"),
+			TreeProblem::InvalidDummy(reason) => write!(f, "The reference timestamp's snapshot is not empty: {}", reason),
+		}
+	}
+}
+
+/// Check that a finalized snapshot tree is being served correctly: that every symlink resolves,
+/// and to the snapshot its timestamp would have been assigned by generation, that the update
+/// time is no older than `max_update_age`, and, if `empty_delta` is set, that the reference
+/// timestamp's symlink leads to an empty snapshot.
+///
+/// The reference timestamp is inferred from the newest symlink, and only timestamp-named
+/// targets can be checked for their assignment. Returns an empty list if the tree is intact.
+pub fn verify_served_tree(cache_path: &str, granularity_interval: u64, snapshot_scopes: &[u64], initial_sync_scope: u64, empty_delta: bool, max_update_age: Duration) -> Vec<TreeProblem> {
+	let symlink_directory = format!("{}/symlinks", cache_path);
+	let snapshot_directory = format!("{}/snapshots", cache_path);
+	let mut problems = Vec::new();
+	if !fs::metadata(&snapshot_directory).map(|metadata| metadata.is_dir()).unwrap_or(false) {
+		problems.push(TreeProblem::MissingDirectory(snapshot_directory));
+	}
+	let entries = match fs::read_dir(&symlink_directory) {
+		Ok(entries) => entries,
+		Err(_) => {
+			problems.push(TreeProblem::MissingDirectory(symlink_directory));
+			return problems;
+		}
+	};
+
+	let mut symlinks = Vec::new();
+	for entry in entries.flatten() {
+		let name = entry.file_name().to_string_lossy().to_string();
+		let timestamp = match name.split_once(".bin") {
+			Some((timestamp, _)) => timestamp.parse::<u64>().ok(),
+			None => continue,
+		};
+		let symlink_path = format!("{}/{}", symlink_directory, name);
+		let target = fs::read_link(&symlink_path).map(|target| target.to_string_lossy().to_string()).unwrap_or_default();
+		if fs::metadata(&symlink_path).is_err() {
+			problems.push(TreeProblem::DanglingSymlink { symlink: symlink_path, target });
+			continue;
+		}
+		// only the uncompressed variants are checked for their assignment
+		if let Some(timestamp) = timestamp.filter(|_| name.ends_with(".bin")) {
+			symlinks.push((timestamp, symlink_path, target));
+		}
+	}
+
+	let newest_timestamp = symlinks.iter().map(|(timestamp, _, _)| *timestamp).max().filter(|timestamp| *timestamp > 0);
+	let reference_timestamp = if empty_delta {
+		match symlinks.iter().find(|(timestamp, _, _)| Some(*timestamp) == newest_timestamp) {
+			Some((reference_timestamp, dummy_path, _)) => {
+				match fs::read(dummy_path).map_err(|error| error.to_string()).and_then(|data| crate::serialization::read_serialized_response(data).map_err(|error| format!("{:?}", error))) {
+					Ok(dummy) if dummy.message_count == 0 => {}
+					Ok(dummy) => problems.push(TreeProblem::InvalidDummy(format!("it contains {} messages", dummy.message_count))),
+					Err(error) => problems.push(TreeProblem::InvalidDummy(error)),
+				}
+				Some(*reference_timestamp)
+			}
+			None => {
+				problems.push(TreeProblem::MissingDummy);
+				None
+			}
+		}
+	} else {
+		// the newest symlink is the one a granularity interval before the reference timestamp
+		newest_timestamp.map(|timestamp| timestamp + granularity_interval)
+	};
+
+	if let Some(reference_timestamp) = reference_timestamp {
+		for (timestamp, symlink_path, target) in &symlinks {
+			if *timestamp == reference_timestamp {
+				continue;
+			}
+			let target_filename = target.rsplit('/').next().unwrap_or_default();
+			if parse_snapshot_filename(target_filename).is_none() {
+				// content-addressed
+				continue;
+			}
+			let expected = resolve_snapshot(*timestamp, reference_timestamp, granularity_interval, snapshot_scopes, initial_sync_scope);
+			match expected {
+				Some((_, expected_filename)) if expected_filename == target_filename => {}
+				expected => problems.push(TreeProblem::MisdirectedSymlink {
+					symlink: symlink_path.clone(),
+					target: target.clone(),
+					expected: expected.map(|(_, filename)| filename).unwrap_or("no snapshot".to_string()),
+				}),
+			}
+		}
+	}
+
+	let update_time = fs::read_to_string(format!("{}/update_time.txt", symlink_directory)).ok().and_then(|update_time| update_time.trim().parse::<u64>().ok());
+	match update_time {
+		Some(update_time) => {
+			let current_timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+			if current_timestamp.saturating_sub(update_time) > max_update_age.as_secs() {
+				problems.push(TreeProblem::StaleUpdateTime(update_time));
+			}
+		}
+		None => problems.push(TreeProblem::MissingUpdateTime),
+	}
+	problems
+}
+
+/// The total size of the files and symlinks below `path`, and their number including the
+/// directories, without following symlinks. Entries that vanish or can't be read during the walk
+/// are skipped, because the totals are only informational.
+fn measure_tree(path: &str) -> (u64, u64) {
+	let mut disk_usage_bytes = 0;
+	let mut inode_count = 0;
+	let mut pending_directories = vec![PathBuf::from(path)];
+	while let Some(directory) = pending_directories.pop() {
+		let entries = match fs::read_dir(&directory) {
+			Ok(entries) => entries,
+			Err(_) => continue,
+		};
+		for entry in entries.flatten() {
+			// unlike fs::metadata, this describes symlinks themselves
+			let metadata = match entry.metadata() {
+				Ok(metadata) => metadata,
+				Err(_) => continue,
+			};
+			inode_count += 1;
+			if metadata.is_dir() {
+				pending_directories.push(entry.path());
+			} else {
+				disk_usage_bytes += metadata.len();
+			}
+		}
+	}
+	(disk_usage_bytes, inode_count)
+}
+
+/// Create the cache path if it doesn't exist yet, and make sure that files can be created in it,
+/// so that a fresh volume needs no preparation and an unusable one is reported before syncing.
+pub(crate) fn prepare_cache_path(cache_path: &str) -> std::io::Result<()> {
+	fs::create_dir_all(cache_path)?;
+	let probe_path = format!("{}/.rgs.probe", cache_path);
+	fs::write(&probe_path, [])?;
+	fs::remove_file(&probe_path)
+}
+
+/// An advisory lock on the cache path, preventing two snapshotters from clobbering each other's
+/// pending directories. The lock is released when this is dropped, or when the process exits.
+pub(crate) struct CacheLock {
+	_file: File,
+}
+
+impl CacheLock {
+	pub(crate) fn acquire(cache_path: &str) -> Result<Self, String> {
+		prepare_cache_path(cache_path).map_err(|error| format!("Cache path {} is not usable: {}", cache_path, error))?;
+		let lock_path = format!("{}/.rgs.lock", cache_path);
+		let mut file = OpenOptions::new()
+			.read(true)
+			.write(true)
+			.create(true)
+			.truncate(false)
+			.open(&lock_path)
+			.map_err(|error| format!("Failed to open cache lock file {}: {}", lock_path, error))?;
+		if let Err(error) = file.try_lock_exclusive() {
+			if error.raw_os_error() == fs2::lock_contended_error().raw_os_error() {
+				let holder = fs::read_to_string(&lock_path).unwrap_or_default();
+				let holder = holder.trim();
+				if holder.is_empty() {
+					return Err(format!("Another instance is already using cache path {}", cache_path));
+				}
+				return Err(format!("Another instance (PID {}) is already using cache path {}", holder, cache_path));
+			}
+			return Err(format!("Failed to lock cache path {}: {}", cache_path, error));
+		}
+		file.set_len(0).and_then(|()| write!(file, "{}", std::process::id()))
+			.map_err(|error| format!("Failed to write cache lock file {}: {}", lock_path, error))?;
+		Ok(Self { _file: file })
+	}
+}
+
+pub(crate) fn create_symlinks<L: Deref>(target_path: &str, symlink_path: &str, variants: &[SnapshotCompression], logger: &L) -> std::io::Result<()> where L::Target: Logger {
+	for variant in variants {
+		let extension = variant.file_extension();
+		create_symlink(&format!("{}{}", target_path, extension), &format!("{}{}", symlink_path, extension), logger)?;
+	}
+	Ok(())
+}
+
+/// Create a symlink at `symlink_path`, replacing whatever entry might already be there.
+///
+/// The pending directories are purged at the start of every cycle, so an existing entry means
+/// either that the purge was incomplete, or that another process is writing to the same cache
+/// path.
+fn create_symlink<L: Deref>(target_path: &str, symlink_path: &str, logger: &L) -> std::io::Result<()> where L::Target: Logger {
+	let with_path = |error: std::io::Error| std::io::Error::new(error.kind(), format!("{}: {}", symlink_path, error));
+	match symlink(target_path, symlink_path) {
+		Err(error) if error.kind() == ErrorKind::AlreadyExists => {
+			log_warn!(logger, "Symlink {} already exists, replacing it. Is another instance using the same cache path?", symlink_path);
+			fs::remove_file(symlink_path).map_err(with_path)?;
+			symlink(target_path, symlink_path).map_err(with_path)
+		}
+		result => result.map_err(with_path),
+	}
+}
+
+/// Remove the symlinks in `symlink_directory` whose targets don't exist, returning how many were
+/// removed.
+///
+/// A cycle interrupted between finalizing the symlink and the snapshot directories may leave the
+/// finalized tree pointing at snapshots that are gone. The next cycle replaces the whole tree, so
+/// this only shortens the window in which clients are served errors for those timestamps.
+pub(crate) fn remove_dangling_symlinks<L: Deref>(symlink_directory: &str, logger: &L) -> usize where L::Target: Logger {
+	let entries = match fs::read_dir(symlink_directory) {
+		Ok(entries) => entries,
+		Err(_) => return 0,
+	};
+	let mut removed_count = 0;
+	for entry in entries.flatten() {
+		let path = entry.path();
+		let is_symlink = entry.file_type().map(|file_type| file_type.is_symlink()).unwrap_or(false);
+		if !is_symlink || fs::metadata(&path).is_ok() {
+			continue;
+		}
+		match fs::remove_file(&path) {
+			Ok(()) => removed_count += 1,
+			Err(error) => log_warn!(logger, "Failed to remove dangling symlink {}: {}", path.display(), error),
+		}
+	}
+	if removed_count > 0 {
+		log_warn!(logger, "Removed {} dangling symlinks from {}", removed_count, symlink_directory);
+	}
+	removed_count
+}
+
+/// Record the symlink grid, i. e. the anchor timestamp and the granularity, in
+/// `{cache_path}/symlink_grid.txt`, warning if the previously recorded grid had timestamps that
+/// the new one lacks. Clients that cached one of those as their last sync timestamp will no longer
+/// find a symlink for it.
+///
+/// Returns whether any timestamps were dropped from the grid.
+pub(crate) fn record_symlink_grid<L: Deref>(cache_path: &str, anchor_timestamp: u64, granularity_interval: u64, logger: &L) -> bool where L::Target: Logger {
+	let grid_path = format!("{}/symlink_grid.txt", cache_path);
+	let previous_grid = fs::read_to_string(&grid_path).ok().and_then(|grid| {
+		let (anchor_timestamp, granularity_interval) = grid.trim().split_once(' ')?;
+		Some((anchor_timestamp.parse::<u64>().ok()?, granularity_interval.parse::<u64>().ok()?))
+	});
+	// the grid only keeps all previous timestamps if it got finer and reaches back further
+	let dropped_timestamps = previous_grid.map_or(false, |(previous_anchor_timestamp, previous_granularity_interval)| {
+		previous_granularity_interval % granularity_interval != 0 || anchor_timestamp > previous_anchor_timestamp
+	});
+	match previous_grid {
+		Some((previous_anchor_timestamp, previous_granularity_interval)) if dropped_timestamps => {
+			log_warn!(logger, "Symlink grid changed from every {}s after {} to every {}s after {}. Clients whose last sync timestamp isn't on the new grid will miss their symlinks.", previous_granularity_interval, previous_anchor_timestamp, granularity_interval, anchor_timestamp);
+		}
+		Some(previous_grid) if previous_grid != (anchor_timestamp, granularity_interval) => {
+			log_info!(logger, "Symlink grid changed from every {}s after {} to every {}s after {}", previous_grid.1, previous_grid.0, granularity_interval, anchor_timestamp);
+		}
+		_ => {}
+	}
+	if let Err(error) = fs::write(&grid_path, format!("{} {}", anchor_timestamp, granularity_interval)) {
+		log_warn!(logger, "Failed to record symlink grid in {}: {}", grid_path, error);
+	}
+	dropped_timestamps
+}
+
+/// Recreate the symlink tree of the finalized snapshot set in the cache path from the snapshot
+/// files' names alone, without regenerating any snapshots, e.g. after the symlinks were lost.
+///
+/// The snapshot set is taken to be the timestamp-named files of the newest reference timestamp,
+/// whose scopes must include the full sync. The symlinks are laid out according to `config`, the
+/// same way a cycle with it would, and are written to a pending directory first, which then
+/// replaces the finalized one. Returns the number of timestamps that were symlinked.
+pub(crate) fn rebuild_symlinks<L: Deref>(config: &SnapshotterConfig, logger: &L) -> Result<u64, String> where L::Target: Logger {
+	let cache_path = &config.cache_path;
+	let snapshot_directory = format!("{}/snapshots", cache_path);
+	let entries = fs::read_dir(&snapshot_directory).map_err(|error| format!("Failed to read {}: {}", snapshot_directory, error))?;
+	let mut snapshot_files: Vec<(u64, u64, String)> = entries.flatten().filter_map(|entry| {
+		let filename = entry.file_name().to_string_lossy().to_string();
+		let (reference_timestamp, scope, _) = parse_snapshot_filename(&filename)?;
+		Some((reference_timestamp, scope, filename))
+	}).collect();
+	let reference_timestamp = snapshot_files.iter().map(|(reference_timestamp, _, _)| *reference_timestamp).max()
+		.ok_or(format!("No timestamp-named snapshot files in {}", snapshot_directory))?;
+	snapshot_files.retain(|(file_reference_timestamp, _, _)| *file_reference_timestamp == reference_timestamp);
+	let snapshot_filenames_by_scope: HashMap<u64, String> = snapshot_files.into_iter().map(|(_, scope, filename)| (scope, filename)).collect();
+	if !snapshot_filenames_by_scope.contains_key(&u64::MAX) {
+		return Err(format!("No full snapshot was generated at {}", reference_timestamp));
+	}
+	let mut snapshot_scopes: Vec<u64> = snapshot_filenames_by_scope.keys().copied().collect();
+	snapshot_scopes.sort_unstable();
+	let initial_sync_scope = config.initial_sync_scope.filter(|scope| snapshot_scopes.contains(scope)).unwrap_or(u64::MAX);
+
+	// only symlink the variants that were actually written
+	let full_snapshot_path = format!("{}/{}", snapshot_directory, snapshot_filenames_by_scope[&u64::MAX]);
+	let mut variants = vec![SnapshotCompression::Identity];
+	variants.extend(config.snapshot_compressions.iter().copied().filter(|variant| fs::metadata(format!("{}{}", full_snapshot_path, variant.file_extension())).is_ok()));
+
+	let pending_symlink_directory = format!("{}/symlinks_pending", cache_path);
+	let finalized_symlink_directory = format!("{}/symlinks", cache_path);
+	remove_dir_if_exists(&pending_symlink_directory).map_err(|error| format!("Failed to remove pending symlink directory: {}", error))?;
+	fs::create_dir_all(&pending_symlink_directory).map_err(|error| format!("Failed to create pending symlink directory: {}", error))?;
+
+	let symlink_to_snapshot_path = &config.symlink_target_path;
+	let symlink_plan = plan_symlinks(reference_timestamp, config.symlink_granularity_interval, config.symlink_anchor_timestamp, &snapshot_scopes, initial_sync_scope, config.max_symlink_count, |scope| {
+		snapshot_filenames_by_scope[&scope].clone()
+	});
+	let symlink_count = symlink_plan.len() as u64;
+	for (symlink_name, snapshot_filename) in symlink_plan {
+		let relative_snapshot_path = format!("{}/{}", symlink_to_snapshot_path, snapshot_filename);
+		create_symlinks(&relative_snapshot_path, &format!("{}/{}", pending_symlink_directory, symlink_name), &variants, logger)
+			.map_err(|error| format!("Failed to create symlink {}", error))?;
+	}
+	if fs::metadata(format!("{}/empty_delta.lngossip", snapshot_directory)).is_ok() {
+		let relative_dummy_snapshot_path = format!("{}/empty_delta.lngossip", symlink_to_snapshot_path);
+		create_symlinks(&relative_dummy_snapshot_path, &format!("{}/{}.bin", pending_symlink_directory, reference_timestamp), &variants, logger)
+			.map_err(|error| format!("Failed to create symlink {}", error))?;
+	}
+	if config.latest_symlink {
+		let relative_full_snapshot_path = format!("{}/{}", symlink_to_snapshot_path, snapshot_filenames_by_scope[&u64::MAX]);
+		create_symlinks(&relative_full_snapshot_path, &format!("{}/latest.lngossip", pending_symlink_directory), &variants, logger)
+			.map_err(|error| format!("Failed to create symlink {}", error))?;
+	}
+
+	// the snapshots were last updated when the full snapshot was written, not now
+	let update_time = fs::metadata(&full_snapshot_path).and_then(|metadata| metadata.modified())
+		.map_err(|error| format!("Failed to read modification time of {}: {}", full_snapshot_path, error))?
+		.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+	fs::write(format!("{}/update_time.txt", pending_symlink_directory), update_time.to_string()).map_err(|error| format!("Failed to write update time: {}", error))?;
+
+	remove_dir_if_exists(&finalized_symlink_directory).map_err(|error| format!("Failed to remove finalized symlink directory: {}", error))?;
+	fs::rename(&pending_symlink_directory, &finalized_symlink_directory).map_err(|error| format!("Failed to finalize symlink directory: {}", error))?;
+	log_info!(logger, "Rebuilt {} symlinks for the snapshots generated at {}", symlink_count, reference_timestamp);
+	Ok(symlink_count)
+}
+
+/// How many snapshot events are buffered for each subscriber. Subscribers that fall further
+/// behind miss the oldest events rather than stalling snapshot generation.
+pub(crate) const SNAPSHOT_EVENT_CAPACITY: usize = 16;
+
+/// Statistics about a single snapshot scope generated during a cycle
+#[derive(Clone, Debug)]
+pub struct SnapshotScopeStats {
+	pub scope: u64,
+	pub filename: String,
+	/// The size of the uncompressed snapshot
+	pub size_bytes: usize,
+	pub message_count: u32,
+	pub announcement_count: u32,
+	pub update_count: u32,
+	/// How long it took to serialize and persist the snapshot
+	pub duration: Duration,
+}
+
+/// Published after every snapshot cycle whose snapshots have been finalized
+#[derive(Clone, Debug)]
+pub struct SnapshotEvent {
+	pub reference_timestamp: u64,
+	/// The serialization format version of the snapshots
+	pub format_version: u8,
+	pub scopes: Vec<SnapshotScopeStats>,
+	/// The cache path containing the finalized `snapshots` and `symlinks` directories
+	pub output_dir: String,
+	/// The total size of the files and symlinks below the cache path once the set was finalized,
+	/// including any previous set, stale pending directories, and files not written by the cycles
+	pub disk_usage_bytes: u64,
+	/// The number of files, symlinks, and directories below the cache path, counted alongside
+	/// `disk_usage_bytes`
+	pub inode_count: u64,
+	/// The newest channel update timestamp in the network graph at capture time, or `None` if the
+	/// graph holds no updates. If this lags the reference timestamp significantly, the gossip feed
+	/// has likely stalled, and the snapshots are stale despite being generated on schedule.
+	pub newest_update_timestamp: Option<u32>,
+	/// How long after startup the first snapshots were finalized, if known
+	pub time_to_first_snapshot: Option<Duration>,
+	/// How long it took from capturing the snapshots until they were finalized
+	pub cycle_duration: Duration,
+	/// Whether the cycle took longer than the configured share of the snapshot interval, meaning
+	/// that cycles are at risk of overrunning the interval and skipping reference timestamps
+	pub exceeded_duration_threshold: bool,
+	/// How long after the reference timestamp the snapshots were captured. The served snapshots
+	/// lag the graph by at least this much, so a growing delay means that cycles are running late.
+	pub capture_delay: Duration,
+	/// Whether the capture delay exceeded the configured threshold
+	pub exceeded_capture_delay_threshold: bool,
+}
+
+/// A transformation of every snapshot file's contents, e. g. encryption at rest or a custom
+/// trailer, applied to the serialized snapshot just before it is written.
+///
+/// Clients must reverse it themselves. The transformed contents are what content-addressed
+/// filenames are derived from, what compressed variants are compressed from, and what is counted
+/// towards the disk usage. Uploads and socket pushes are not transformed, and
+/// [`verify_served_tree`] can only check the empty snapshot if it is left intact.
+pub type SnapshotTransform = fn(&[u8]) -> Vec<u8>;
+
+/// A source of the current time, so that cycles can be driven by something other than the system
+/// clock, e. g. in tests
+pub(crate) type Clock = fn() -> SystemTime;
+
+/// The settings a [`Snapshotter`] schedules, serializes, and generates its cycles with, each
+/// resolved once from the configuration function of the same name.
+///
+/// The upload client and API key are passed separately, see [`UploadCredentials`].
+#[derive(Clone, Debug)]
+pub(crate) struct SnapshotterConfig {
+	pub(crate) cache_path: String,
+	/// Also holds the snapshot interval
+	pub(crate) serialization: SerializationConfig,
+	pub(crate) snapshot_scopes: Vec<u64>,
+	/// Limits the number of symlinks created per cycle, which is never configured
+	pub(crate) max_symlink_count: Option<u64>,
+	pub(crate) initial_snapshot_delay: Duration,
+	pub(crate) symlink_granularity_interval: u64,
+	pub(crate) symlink_anchor_timestamp: u64,
+	pub(crate) symlink_target_path: String,
+	pub(crate) symlink_time_budget: Option<Duration>,
+	pub(crate) symlink_threads: usize,
+	pub(crate) initial_sync_scope: Option<u64>,
+	pub(crate) generate_empty_delta: bool,
+	pub(crate) latest_symlink: bool,
+	pub(crate) snapshot_compressions: Vec<SnapshotCompression>,
+	pub(crate) content_addressed_filenames: bool,
+	pub(crate) scid_index: bool,
+	pub(crate) keep_previous_snapshots: bool,
+	pub(crate) max_snapshot_bytes: Option<usize>,
+	pub(crate) max_graph_shrink_percent: Option<u64>,
+	pub(crate) max_graph_shrink_refusals: u32,
+	pub(crate) capture_delay_alert_threshold: Duration,
+	pub(crate) cycle_duration_alert_percent: u64,
+	pub(crate) upload_scopes: Vec<u64>,
+	pub(crate) upload_content_encoding: &'static str,
+	pub(crate) upload_from_file: bool,
+	pub(crate) skip_unchanged_uploads: bool,
+	pub(crate) min_upload_interval: Option<Duration>,
+	pub(crate) post_snapshot_command: Option<String>,
+	pub(crate) log_cycle_summary: bool,
+}
+
+impl SnapshotterConfig {
+	pub(crate) fn from_config() -> Self {
+		let serialization = SerializationConfig::from_config();
+		Self {
+			cache_path: config::cache_path(),
+			snapshot_scopes: configured_snapshot_scopes(serialization.snapshot_interval),
+			serialization,
+			max_symlink_count: None,
+			initial_snapshot_delay: config::initial_snapshot_delay(),
+			symlink_granularity_interval: config::symlink_granularity_interval() as u64,
+			symlink_anchor_timestamp: config::symlink_anchor_timestamp(),
+			symlink_target_path: config::symlink_target_path(),
+			symlink_time_budget: config::symlink_time_budget(),
+			symlink_threads: config::symlink_threads(),
+			initial_sync_scope: config::initial_sync_scope(),
+			generate_empty_delta: config::generate_empty_delta(),
+			latest_symlink: config::latest_symlink(),
+			snapshot_compressions: config::snapshot_compressions(),
+			content_addressed_filenames: config::content_addressed_filenames(),
+			scid_index: config::scid_index(),
+			keep_previous_snapshots: config::keep_previous_snapshots(),
+			max_snapshot_bytes: config::max_snapshot_bytes(),
+			max_graph_shrink_percent: config::max_graph_shrink_percent(),
+			max_graph_shrink_refusals: config::max_graph_shrink_refusals(),
+			capture_delay_alert_threshold: config::capture_delay_alert_threshold(),
+			cycle_duration_alert_percent: config::cycle_duration_alert_percent(),
+			upload_scopes: config::upload_scopes(),
+			upload_content_encoding: config::upload_content_encoding(),
+			upload_from_file: config::upload_from_file(),
+			skip_unchanged_uploads: config::skip_unchanged_uploads(),
+			min_upload_interval: config::min_upload_interval(),
+			post_snapshot_command: config::post_snapshot_command(),
+			log_cycle_summary: config::log_cycle_summary(),
+		}
+	}
+}
+
+/// What a [`Snapshotter`] otherwise takes from the configuration and the process
+pub(crate) struct SnapshotterDependencies {
+	/// The settings the cycles are scheduled and generated with
+	pub(crate) config: SnapshotterConfig,
+	/// The Unix domain socket finalized snapshots are pushed to
+	pub(crate) snapshot_socket_path: Option<String>,
+	pub(crate) uploader: UploadCredentials,
+	/// Determines the reference timestamps and update times of the cycles
+	pub(crate) clock: Clock,
+}
+
+impl SnapshotterDependencies {
+	/// The configured dependencies, with the system clock
+	pub(crate) fn from_config() -> Self {
+		Self { config: SnapshotterConfig::from_config(), snapshot_socket_path: config::snapshot_socket_path(), uploader: UploadCredentials::Configured, clock: SystemTime::now }
+	}
+}
 
 pub(crate) struct Snapshotter<L: Deref + Clone> where L::Target: Logger {
 	network_graph: Arc<NetworkGraph<L>>,
 	logger: L,
+	event_sender: broadcast::Sender<SnapshotEvent>,
+	/// Kept across cycles, so that the connection can be reused
+	snapshot_socket: Option<tokio::sync::Mutex<SnapshotSocket>>,
+	/// The hash of the last successfully uploaded full snapshot
+	last_uploaded_full_hash: std::sync::Mutex<Option<String>>,
+	/// The filename of the full snapshot in the last finalized set
+	last_full_snapshot_filename: std::sync::Mutex<Option<String>>,
+	/// The number of channels announced in the full snapshot of the last finalized set
+	last_full_announcement_count: std::sync::Mutex<Option<u32>>,
+	/// How many cycles in a row were abandoned for the full snapshot's channel count dropping
+	graph_shrink_refusals: std::sync::Mutex<u32>,
+	/// When the last successful upload was started
+	last_upload_start: std::sync::Mutex<Option<Instant>>,
+	transform: Option<SnapshotTransform>,
+	/// When the process started, and how long after that the first snapshots were finalized
+	startup: Option<(Instant, Arc<Mutex<Option<Duration>>>)>,
+	uploader: UploadCredentials,
+	clock: Clock,
+	config: SnapshotterConfig,
 }
 
-impl<L: Deref + Clone> Snapshotter<L> where L::Target: Logger {
-	pub fn new(network_graph: Arc<NetworkGraph<L>>, logger: L) -> Self {
-		Self { network_graph, logger }
+impl<L: Deref + Clone + Send + 'static> Snapshotter<L> where L::Target: Logger {
+	pub fn new(network_graph: Arc<NetworkGraph<L>>, logger: L, event_sender: broadcast::Sender<SnapshotEvent>) -> Self {
+		Self::with_dependencies(network_graph, logger, event_sender, SnapshotterDependencies::from_config())
+	}
+
+	/// Like [`Self::new`], but with the dependencies given explicitly rather than configured
+	pub(crate) fn with_dependencies(network_graph: Arc<NetworkGraph<L>>, logger: L, event_sender: broadcast::Sender<SnapshotEvent>, dependencies: SnapshotterDependencies) -> Self {
+		let SnapshotterDependencies { config, snapshot_socket_path, uploader, clock } = dependencies;
+		let snapshot_socket = snapshot_socket_path.map(|path| tokio::sync::Mutex::new(SnapshotSocket::new(path)));
+		Self { network_graph, logger, event_sender, snapshot_socket, last_uploaded_full_hash: std::sync::Mutex::new(None), last_full_snapshot_filename: std::sync::Mutex::new(None), last_full_announcement_count: std::sync::Mutex::new(None), graph_shrink_refusals: std::sync::Mutex::new(0), last_upload_start: std::sync::Mutex::new(None), transform: None, startup: None, uploader, clock, config }
 	}
 
+	pub(crate) fn config(&self) -> &SnapshotterConfig {
+		&self.config
+	}
+
+	fn current_timestamp(&self) -> u64 {
+		(self.clock)().duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO).as_secs()
+	}
+
+	/// Apply a transform to every snapshot file's contents before it is written
+	pub(crate) fn with_transform(mut self, transform: Option<SnapshotTransform>) -> Self {
+		self.transform = transform;
+		self
+	}
+
+	/// Record how long after `startup` the first snapshots are finalized in `time_to_first_snapshot`
+	pub(crate) fn with_startup(mut self, startup: Instant, time_to_first_snapshot: Arc<Mutex<Option<Duration>>>) -> Self {
+		self.startup = Some((startup, time_to_first_snapshot));
+		self
+	}
+
+	/// Upload snapshots with `uploader` rather than with the configured credentials
+	pub(crate) fn with_uploader(mut self, uploader: UploadCredentials) -> Self {
+		self.uploader = uploader;
+		self
+	}
+
+	fn transform_file_data<'a>(&self, data: &'a [u8]) -> Cow<'a, [u8]> {
+		match self.transform {
+			Some(transform) => Cow::Owned(transform(data)),
+			None => Cow::Borrowed(data),
+		}
+	}
+
+	/// Run the snapshotting service indefinitely.
+	///
+	/// The first set of snapshots is generated immediately upon invocation, which happens as soon
+	/// as the initial gossip sync has caught up, so there is no need to wait for the next aligned
+	/// interval on a fresh deploy, unless an initial delay is configured to let the graph warm
+	/// up. Subsequent generations are scheduled at multiples of the snapshot interval.
+	///
+	/// The caller must hold the [`CacheLock`] for as long as this runs.
 	pub(crate) async fn snapshot_gossip(&self) {
 		log_info!(self.logger, "Initiating snapshotting service");
 
-		let snapshot_interval = config::snapshot_generation_interval() as u64;
-		let mut snapshot_scopes = vec![];
-		{ // double the coefficient until it reaches the maximum (limited) snapshot scope
-			let mut current_scope = snapshot_interval;
-			loop {
-				snapshot_scopes.push(current_scope);
-				if current_scope >= config::MAX_SNAPSHOT_SCOPE as u64 {
-					snapshot_scopes.push(u64::MAX);
-					break;
-				}
+		let snapshot_interval = self.config.serialization.snapshot_interval;
 
-				// double the current factor
-				current_scope <<= 1;
-			}
+		let initial_delay = self.config.initial_snapshot_delay;
+		if !initial_delay.is_zero() {
+			log_info!(self.logger, "Waiting {:?} before the first snapshot capture", initial_delay);
+			tokio::time::sleep(initial_delay).await;
 		}
 
 		// this is gonna be a never-ending background job
+		// note that the first iteration doesn't wait, so snapshots are available right after startup
 		loop {
-			self.generate_snapshots(config::SYMLINK_GRANULARITY_INTERVAL as u64, snapshot_interval, &snapshot_scopes, &cache_path(), None).await;
+			// Anchor the schedule to the wall clock once per cycle, and derive the deadline from the
+			// monotonic clock from there on, so that the wall clock being adjusted while the
+			// snapshots are being constructed can't make us fire twice or oversleep.
+			let cycle_start = tokio::time::Instant::now();
+			let anchor_time = self.current_timestamp();
 
-			// constructing the snapshots may have taken a while
-			let current_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+			self.generate_snapshots().await;
 
 			// NOTE: we're waiting until the next multiple of snapshot_interval
 			// however, if the symlink granularity is lower, then during that time, no intermediate
 			// symlinks will be generated. That should be ok, because any timestamps previously
 			// returned would already have generated symlinks, but this does have bug potential
-			let remainder = current_time % snapshot_interval;
-			let time_until_next_generation = snapshot_interval - remainder;
-
-			log_info!(self.logger, "Sleeping until next snapshot capture: {}s", time_until_next_generation);
+			let remainder = anchor_time % snapshot_interval;
 			// add in an extra five seconds to assure the rounding down works correctly
-			let sleep = tokio::time::sleep(Duration::from_secs(time_until_next_generation + 5));
-			sleep.await;
+			let mut next_generation = cycle_start + Duration::from_secs(snapshot_interval - remainder + 5);
+
+			// constructing the snapshots may have taken longer than an interval
+			let now = tokio::time::Instant::now();
+			while next_generation <= now {
+				next_generation += Duration::from_secs(snapshot_interval);
+			}
+
+			log_info!(self.logger, "Sleeping until next snapshot capture: {}s", (next_generation - now).as_secs());
+			tokio::time::sleep_until(next_generation).await;
 		}
 	}
 
-	pub(crate) async fn generate_snapshots(&self, granularity_interval: u64, snapshot_interval: u64, snapshot_scopes: &[u64], cache_path: &str, max_symlink_count: Option<u64>) {
+	/// Generate and finalize a single set of snapshots.
+	///
+	/// The snapshot interval must be a multiple of the symlink granularity, which the configuration
+	/// is validated for at startup. Otherwise, the reference timestamps of consecutive cycles are
+	/// not aligned to the symlink grid, so the timestamps clients were handed by a previous cycle's
+	/// snapshots may have no symlink in the next one.
+	///
+	/// Returns the event published to subscribers, or `None` if the cycle was abandoned, in which
+	/// case the reason has been logged.
+	pub(crate) async fn generate_snapshots(&self) -> Option<SnapshotEvent> {
+		let granularity_interval = self.config.symlink_granularity_interval;
+		let snapshot_interval = self.config.serialization.snapshot_interval;
+		let snapshot_scopes = &self.config.snapshot_scopes;
+		let cache_path = &self.config.cache_path;
+		let max_symlink_count = self.config.max_symlink_count;
 		let pending_snapshot_directory = format!("{}/snapshots_pending", cache_path);
 		let pending_symlink_directory = format!("{}/symlinks_pending", cache_path);
-		let finalized_snapshot_directory = format!("{}/snapshots", cache_path);
-		let finalized_symlink_directory = format!("{}/symlinks", cache_path);
-		let relative_symlink_to_snapshot_path = "../snapshots";
+		let symlink_to_snapshot_path = self.config.symlink_target_path.clone();
+
+		// the uncompressed variant is always written
+		let mut file_variants = vec![SnapshotCompression::Identity];
+		file_variants.extend(self.config.snapshot_compressions.iter().copied());
 
 		// 1. get the current timestamp
-		let snapshot_generation_timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+		let snapshot_generation_timestamp = self.current_timestamp();
 		let reference_timestamp = Self::round_down_to_nearest_multiple(snapshot_generation_timestamp, snapshot_interval as u64);
 		log_info!(self.logger, "Capturing snapshots at {} for: {}", snapshot_generation_timestamp, reference_timestamp);
+		let capture_delay = Duration::from_secs(snapshot_generation_timestamp - reference_timestamp);
+		let capture_delay_threshold = self.config.capture_delay_alert_threshold;
+		let exceeded_capture_delay_threshold = capture_delay > capture_delay_threshold;
+		if exceeded_capture_delay_threshold {
+			log_warn!(self.logger, "Capturing snapshots {:?} after their reference timestamp, exceeding {:?}. Clients are served snapshots lagging the graph by at least as much.", capture_delay, capture_delay_threshold);
+		} else {
+			log_debug!(self.logger, "Capturing snapshots {:?} after their reference timestamp", capture_delay);
+		}
+		let cycle_start = Instant::now();
+
+		let newest_update_timestamp = self.newest_update_timestamp();
+		match newest_update_timestamp {
+			Some(timestamp) => log_info!(self.logger, "Newest channel update in the graph is from {} ({}s before capture)", timestamp, snapshot_generation_timestamp.saturating_sub(timestamp as u64)),
+			None => log_warn!(self.logger, "The network graph holds no channel updates"),
+		}
 
 		// 2. sleep until the next round interval
 		// 3. refresh all snapshots
@@ -90,6 +936,10 @@ impl<L: Deref + Clone> Snapshotter<L> where L::Target: Logger {
 		// The snapshots, unlike dynamic updates, should account for all intermediate
 		// channel updates
 
+		remove_dangling_symlinks(&format!("{}/symlinks", cache_path), &self.logger);
+		let anchor_timestamp = self.config.symlink_anchor_timestamp;
+		record_symlink_grid(cache_path, anchor_timestamp, granularity_interval, &self.logger);
+
 		// purge and recreate the pending directories
 		if fs::metadata(&pending_snapshot_directory).is_ok() {
 			fs::remove_dir_all(&pending_snapshot_directory).expect("Failed to remove pending snapshot directory.");
@@ -100,36 +950,187 @@ impl<L: Deref + Clone> Snapshotter<L> where L::Target: Logger {
 		fs::create_dir_all(&pending_snapshot_directory).expect("Failed to create pending snapshot directory");
 		fs::create_dir_all(&pending_symlink_directory).expect("Failed to create pending symlink directory");
 
-		let mut snapshot_sync_timestamps: Vec<(u64, u64)> = Vec::new();
-		for current_scope in snapshot_scopes {
-			let timestamp = reference_timestamp.saturating_sub(current_scope.clone());
-			snapshot_sync_timestamps.push((current_scope.clone(), timestamp));
-		};
+		let snapshot_sync_timestamps = snapshot_sync_timestamps(reference_timestamp, snapshot_scopes);
 
 		let mut snapshot_filenames_by_scope: HashMap<u64, String> = HashMap::with_capacity(10);
+		let max_snapshot_bytes = self.config.max_snapshot_bytes;
+		let max_graph_shrink_percent = self.config.max_graph_shrink_percent;
+		let mut full_announcement_count = None;
+		let content_addressed = self.config.content_addressed_filenames;
+		let upload_content_encoding = self.config.upload_content_encoding;
+		let skip_unchanged_uploads = self.config.skip_unchanged_uploads;
+		let upload_scopes = &self.config.upload_scopes;
+		// checked once per cycle, so that either all or none of a cycle's scopes are uploaded
+		let since_last_upload = self.last_upload_start.lock().unwrap().map(|last_upload_start| last_upload_start.elapsed());
+		let upload_throttle = since_last_upload.zip(self.config.min_upload_interval).filter(|(elapsed, interval)| elapsed < interval);
+		let upload_from_file = self.config.upload_from_file;
+		let scid_index = self.config.scid_index;
+		let mut scope_stats = Vec::with_capacity(snapshot_sync_timestamps.len());
+		// only retained if they are pushed to a socket once finalized
+		let mut socket_snapshots = Vec::new();
 
 		for (current_scope, current_last_sync_timestamp) in &snapshot_sync_timestamps {
 			let network_graph_clone = self.network_graph.clone();
 			{
-				log_info!(self.logger, "Calculating {}-second snapshot", current_scope);
+				log_debug!(self.logger, "Calculating {}-second snapshot", current_scope);
+				let scope_start = Instant::now();
 				// calculate the snapshot
-				let snapshot = super::serialize_delta(network_graph_clone, current_last_sync_timestamp.clone() as u32, self.logger.clone()).await;
+				let snapshot = if *current_scope == u64::MAX {
+					super::serialize_full_graph_with_config(network_graph_clone, reference_timestamp, &self.config.serialization, self.logger.clone()).await
+				} else {
+					super::serialize_delta(network_graph_clone, current_last_sync_timestamp.clone() as u32, &self.config.serialization, self.logger.clone()).await
+				};
+				let snapshot = snapshot.map_err(|error| format!("Failed to serialize {}-second snapshot: {}", current_scope, error));
+				let snapshot = if *current_scope == u64::MAX {
+					snapshot.and_then(|snapshot| {
+						self.self_test_full_snapshot(&snapshot, reference_timestamp)
+							.map_err(|error| format!("Full snapshot failed the self-test: {}", error))?;
+						Ok(snapshot)
+					})
+				} else {
+					snapshot
+				};
+				let previous_full_snapshot_filename = self.last_full_snapshot_filename.lock().unwrap().clone();
+				let mut snapshot = match (snapshot, previous_full_snapshot_filename) {
+					(Ok(snapshot), _) => snapshot,
+					(Err(error), Some(previous_filename)) if *current_scope == u64::MAX => {
+						// the previous full snapshot is still correct, merely outdated, so only those
+						// clients that can't be served a delta miss out on this cycle's updates. Its
+						// contents, and hence a content-addressed name, are unchanged.
+						let filename = if content_addressed {
+							previous_filename.clone()
+						} else {
+							snapshot_filename(reference_timestamp, *current_scope, *current_last_sync_timestamp)
+						};
+						if let Err(retain_error) = self.retain_previous_full_snapshot(cache_path, &pending_snapshot_directory, &previous_filename, &filename, &file_variants) {
+							log_error!(self.logger, "{}. Failed to retain the previous full snapshot {} ({}), keeping the previous snapshots.", error, previous_filename, retain_error);
+							return self.abandon_cycle(&pending_snapshot_directory, &pending_symlink_directory);
+						}
+						log_error!(self.logger, "{}. Serving the previous full snapshot {} as {} instead.", error, previous_filename, filename);
+						snapshot_filenames_by_scope.insert(u64::MAX, filename);
+						continue;
+					}
+					(Err(error), _) => {
+						// the symlinks of every scope are needed, so the whole cycle is given up
+						log_error!(self.logger, "{}. Keeping the previous snapshots.", error);
+						return self.abandon_cycle(&pending_snapshot_directory, &pending_symlink_directory);
+					}
+				};
+
+				if let Some(max_snapshot_bytes) = max_snapshot_bytes {
+					if snapshot.data.len() > max_snapshot_bytes {
+						// refuse to publish anything from this cycle, keeping the last good set
+						log_error!(self.logger, "{}-second snapshot is {} bytes, exceeding the maximum of {} bytes. Keeping the previous snapshots.", current_scope, snapshot.data.len(), max_snapshot_bytes);
+						return self.abandon_cycle(&pending_snapshot_directory, &pending_symlink_directory);
+					}
+				}
+
+				if *current_scope == u64::MAX {
+					let previous_announcement_count = *self.last_full_announcement_count.lock().unwrap();
+					if let Some((previous_announcement_count, max_graph_shrink_percent)) = previous_announcement_count.zip(max_graph_shrink_percent) {
+						let min_announcement_count = previous_announcement_count as u64 * (100 - max_graph_shrink_percent) / 100;
+						let mut graph_shrink_refusals = self.graph_shrink_refusals.lock().unwrap();
+						if (snapshot.announcement_count as u64) >= min_announcement_count {
+							*graph_shrink_refusals = 0;
+						} else if *graph_shrink_refusals < self.config.max_graph_shrink_refusals {
+							// the previous count is kept, so this holds until the graph recovers or
+							// enough cycles in a row have been refused
+							*graph_shrink_refusals += 1;
+							log_error!(self.logger, "Full snapshot announces {} channels, down from {} in the previous cycle, which is more than the maximum drop of {}%. Keeping the previous snapshots.", snapshot.announcement_count, previous_announcement_count, max_graph_shrink_percent);
+							return self.abandon_cycle(&pending_snapshot_directory, &pending_symlink_directory);
+						} else {
+							// a drop persisting for this long is more likely to be real than an outage
+							*graph_shrink_refusals = 0;
+							log_warn!(self.logger, "Full snapshot announces {} channels, down from {} in the previous cycle, which is more than the maximum drop of {}%. Accepting it as the new baseline after {} consecutive refusals.", snapshot.announcement_count, previous_announcement_count, max_graph_shrink_percent, self.config.max_graph_shrink_refusals);
+						}
+					}
+					full_announcement_count = Some(snapshot.announcement_count);
+				}
 
 				// persist the snapshot and update the symlink
-				let snapshot_filename = format!("snapshot__calculated-at:{}__range:{}-scope__previous-sync:{}.lngossip", reference_timestamp, current_scope, current_last_sync_timestamp);
+				let file_data = self.transform_file_data(&snapshot.data);
+				let snapshot_filename = if content_addressed {
+					content_addressed_filename(&file_data, self.config.serialization.hash_algorithm)
+				} else {
+					snapshot_filename(reference_timestamp, *current_scope, *current_last_sync_timestamp)
+				};
 				let snapshot_path = format!("{}/{}", pending_snapshot_directory, snapshot_filename);
-				log_info!(self.logger, "Persisting {}-second snapshot: {} ({} messages, {} announcements, {} updates ({} full, {} incremental))", current_scope, snapshot_filename, snapshot.message_count, snapshot.announcement_count, snapshot.update_count, snapshot.update_count_full, snapshot.update_count_incremental);
-				fs::write(&snapshot_path, snapshot.data.clone()).unwrap();
+				log_info!(self.logger, "Persisting {}-second snapshot: {} (format version {}, {} bytes, {} messages, {} announcements, {} updates ({} full, {} incremental))", current_scope, snapshot_filename, crate::FORMAT_VERSION, snapshot.data.len(), snapshot.message_count, snapshot.announcement_count, snapshot.update_count, snapshot.update_count_full, snapshot.update_count_incremental);
+				let variant_data = Self::write_snapshot_file(&snapshot_path, &file_data, &file_variants);
+				drop(file_data);
+				let file_sizes: Vec<usize> = variant_data.iter().map(Vec::len).collect();
+				// content-addressed snapshots of identical scopes share a file
+				let indexed = snapshot_filenames_by_scope.values().any(|filename| *filename == snapshot_filename);
+				if scid_index && !indexed {
+					// the index is optional metadata, so the snapshot is published without it
+					if let Err(error) = Self::write_scid_index(&snapshot_path, &snapshot.data) {
+						log_warn!(self.logger, "Failed to write short channel ID index for {}-second snapshot, skipping it: {}", current_scope, error);
+					}
+				}
+				if file_variants.len() > 1 {
+					log_debug!(self.logger, "Persisted {}-second snapshot variants: {}", current_scope, Self::format_file_sizes(&snapshot_filename, &file_variants, &file_sizes));
+				}
+				scope_stats.push(SnapshotScopeStats {
+					scope: *current_scope,
+					filename: snapshot_filename.clone(),
+					size_bytes: snapshot.data.len(),
+					message_count: snapshot.message_count,
+					announcement_count: snapshot.announcement_count,
+					update_count: snapshot.update_count,
+					duration: scope_start.elapsed(),
+				});
 				snapshot_filenames_by_scope.insert(current_scope.clone(), snapshot_filename);
 
-                    // after snapshot, upload results to a server
-                    // only doing this for 0 for now
-                    if let Some(api_key) = config::upload_api_key() {
-                        if *current_scope == u64::MAX {
-                            let client = crate::client::Client::new();
-                            match client.post_snapshot(snapshot, 0, api_key) {
-                                Ok(_) => {
-					                log_info!(self.logger, "posted snapshot: {}", 0);
+                    // after snapshot, upload results to a server, each under the timestamp it's a
+                    // delta from, which is 0 for the full snapshot
+                    let upload_credentials = match self.uploader.resolve() {
+                        _ if !upload_scopes.contains(current_scope) => None,
+                        Ok(upload_credentials) => upload_credentials,
+                        // the snapshot is already written, so only its upload is given up
+                        Err(error) => {
+                            log_warn!(self.logger, "Not uploading {}-second snapshot: {}", current_scope, error);
+                            None
+                        }
+                    };
+                    if let Some((client, api_key)) = upload_credentials {
+                        let snapshot_hash = (*current_scope == u64::MAX).then(|| snapshot.content_hash.clone());
+                        let unchanged = skip_unchanged_uploads && snapshot_hash.is_some() && *self.last_uploaded_full_hash.lock().unwrap() == snapshot_hash;
+                        let upload_timestamp = *current_last_sync_timestamp;
+                        if unchanged {
+                            log_info!(self.logger, "Full snapshot is unchanged since the last upload ({}), skipping upload", snapshot.content_hash);
+                        } else if let Some((elapsed, interval)) = upload_throttle {
+                            log_info!(self.logger, "Last upload was only {:?} ago, skipping {}-second snapshot upload until {:?} have passed", elapsed, current_scope, interval);
+                        } else {
+                            let upload_start = Instant::now();
+                            // reuse the compressed file rather than compressing again
+                            let upload_variant = file_variants.iter().position(|variant| variant.content_encoding() == upload_content_encoding);
+                            let result = match upload_variant {
+                                // the files hold the transformed data, but uploads are untransformed
+                                _ if self.transform.is_some() => client.post_snapshot_ref(&snapshot, upload_timestamp, api_key),
+                                // the data is retained until it's pushed to the socket anyway
+                                _ if upload_from_file && self.snapshot_socket.is_none() => {
+                                    // without a matching variant, the uncompressed file is sent as such
+                                    let index = upload_variant.unwrap_or(0);
+                                    let content_encoding = file_variants[index].content_encoding();
+                                    // don't hold on to the data while uploading
+                                    drop(variant_data);
+                                    let variant_path = format!("{}{}", snapshot_path, file_variants[index].file_extension());
+                                    let snapshot = SerializedResponse { data: std::mem::take(&mut snapshot.data), content_hash: snapshot.content_hash.clone(), ..snapshot };
+                                    client.post_snapshot_file(&variant_path, snapshot, content_encoding, upload_timestamp, api_key)
+                                },
+                                Some(index) if file_variants[index] != SnapshotCompression::Identity => {
+                                    let compressed_data = variant_data.into_iter().nth(index).unwrap();
+                                    client.post_compressed_snapshot(&snapshot, compressed_data, upload_content_encoding, upload_timestamp, api_key)
+                                },
+                                _ => client.post_snapshot_ref(&snapshot, upload_timestamp, api_key),
+                            };
+                            match result {
+                                Ok(outcome) => {
+					                log_info!(self.logger, "posted snapshot: {} ({} bytes, status {}, id: {:?}, url: {:?})", upload_timestamp, outcome.bytes_sent, outcome.status, outcome.id, outcome.url);
+                                    if snapshot_hash.is_some() {
+                                        *self.last_uploaded_full_hash.lock().unwrap() = snapshot_hash;
+                                    }
+                                    *self.last_upload_start.lock().unwrap() = Some(upload_start);
                                 },
                                 Err(e) => {
 					                log_error!(self.logger, "error posted snapshot: {}", e);
@@ -137,86 +1138,395 @@ impl<L: Deref + Clone> Snapshotter<L> where L::Target: Logger {
                             }
                         }
                     }
+
+				if self.snapshot_socket.is_some() {
+					socket_snapshots.push((*current_scope, snapshot.data));
+				}
 			}
 		}
 
-		{
+		let generate_empty_delta = self.config.generate_empty_delta;
+		if generate_empty_delta {
 			// create dummy symlink
-			let dummy_filename = "empty_delta.lngossip";
-			let dummy_snapshot = super::serialize_empty_blob(reference_timestamp);
+			let dummy_snapshot = super::serialize_empty_blob(reference_timestamp, granularity_interval, self.network_graph.get_genesis_hash());
+			let dummy_snapshot = self.transform_file_data(&dummy_snapshot);
+			// the dummy embeds the reference timestamp, so its contents change every cycle
+			let dummy_filename = if content_addressed {
+				content_addressed_filename(&dummy_snapshot, self.config.serialization.hash_algorithm)
+			} else {
+				"empty_delta.lngossip".to_string()
+			};
 			let dummy_snapshot_path = format!("{}/{}", pending_snapshot_directory, dummy_filename);
-			fs::write(&dummy_snapshot_path, dummy_snapshot).unwrap();
+			Self::write_snapshot_file(&dummy_snapshot_path, &dummy_snapshot, &file_variants);
 
 			let dummy_symlink_path = format!("{}/{}.bin", pending_symlink_directory, reference_timestamp);
-			let relative_dummy_snapshot_path = format!("{}/{}", relative_symlink_to_snapshot_path, dummy_filename);
-			log_info!(self.logger, "Symlinking dummy: {} -> {}", dummy_symlink_path, relative_dummy_snapshot_path);
-			symlink(&relative_dummy_snapshot_path, &dummy_symlink_path).unwrap();
+			let relative_dummy_snapshot_path = format!("{}/{}", symlink_to_snapshot_path, dummy_filename);
+			log_debug!(self.logger, "Symlinking dummy: {} -> {}", dummy_symlink_path, relative_dummy_snapshot_path);
+			if let Err(error) = self.create_symlinks(&relative_dummy_snapshot_path, &dummy_symlink_path, &file_variants) {
+				log_error!(self.logger, "Failed to create symlink {}. Keeping the previous snapshots.", error);
+				return self.abandon_cycle(&pending_snapshot_directory, &pending_symlink_directory);
+			}
 		}
 
-		// Number of intervals since Jan 1, 2022, a few months before RGS server was released.
-		let mut symlink_count = (reference_timestamp - 1640995200) / granularity_interval;
-		if let Some(max_symlink_count) = max_symlink_count {
-			// this is primarily useful for testing
-			symlink_count = std::cmp::min(symlink_count, max_symlink_count);
+		// the scope that clients without any prior sync (i. e. requesting timestamp 0) are served
+		let initial_sync_scope = match self.config.initial_sync_scope {
+			Some(scope) if snapshot_scopes.contains(&scope) => scope,
+			Some(scope) => {
+				log_warn!(self.logger, "Configured initial sync scope {} is not a snapshot scope, falling back to full sync", scope);
+				u64::MAX
+			}
+			None => u64::MAX,
 		};
 
-		for i in 0..symlink_count {
-			// let's create non-dummy-symlinks
+		// Symlinks are created in order of increasing age, so if the time budget runs out, only
+		// the oldest timestamps are left without a symlink, and those clients are expected to
+		// fall back to a full sync.
+		let symlink_time_budget = self.config.symlink_time_budget;
+		let symlink_start = Instant::now();
+		let symlink_plan = plan_symlinks(reference_timestamp, granularity_interval, anchor_timestamp, snapshot_scopes, initial_sync_scope, max_symlink_count, |scope| {
+			snapshot_filenames_by_scope.get(&scope).unwrap().clone()
+		});
+		let symlink_count = symlink_plan.len() as u64;
+		let symlinks: Vec<(String, String)> = symlink_plan.into_iter().map(|(symlink_name, snapshot_filename)| {
+			let relative_snapshot_path = format!("{}/{}", symlink_to_snapshot_path, snapshot_filename);
+			let symlink_path = format!("{}/{}", pending_symlink_directory, symlink_name);
+			log_debug!(self.logger, "Symlinking: {} -> {}", symlink_path, relative_snapshot_path);
+			(relative_snapshot_path, symlink_path)
+		}).collect();
+		let created_symlink_count = match self.create_symlinks_in_parallel(symlinks, &file_variants, symlink_start, symlink_time_budget).await {
+			Ok(created_symlink_count) => created_symlink_count,
+			Err(error) => {
+				log_error!(self.logger, "{}. Keeping the previous snapshots.", error);
+				return self.abandon_cycle(&pending_snapshot_directory, &pending_symlink_directory);
+			}
+		};
+		// only the time budget leaves symlinks uncreated
+		if let Some(symlink_time_budget) = symlink_time_budget.filter(|_| created_symlink_count < symlink_count) {
+			log_warn!(self.logger, "Exceeded symlink time budget of {:?}, skipping {} older symlinks", symlink_time_budget, symlink_count - created_symlink_count);
+		}
+		log_info!(self.logger, "Created {} symlinks in {:?}", created_symlink_count, symlink_start.elapsed());
 
-			// first, determine which snapshot range should be referenced
-			let referenced_scope = if i == 0 {
-				// special-case 0 to always refer to a full/initial sync
-				u64::MAX
-			} else {
-				/*
-				We have snapshots for 6-day- and 7-day-intervals, but the next interval is
-				14 days. So if somebody requests an update with a timestamp that is 10 days old,
-				there is no longer a snapshot for that specific interval.
+		let latest_symlink = self.config.latest_symlink;
+		if latest_symlink {
+			// finalized along with all other symlinks, so it never points into a previous set
+			let full_snapshot_path = format!("{}/{}", symlink_to_snapshot_path, snapshot_filenames_by_scope[&u64::MAX]);
+			if let Err(error) = self.create_symlinks(&full_snapshot_path, &format!("{}/latest.lngossip", pending_symlink_directory), &file_variants) {
+				log_error!(self.logger, "Failed to create symlink {}. Keeping the previous snapshots.", error);
+				return self.abandon_cycle(&pending_snapshot_directory, &pending_symlink_directory);
+			}
+		}
 
-				The correct snapshot will be the next highest interval, i. e. for 14 days.
+		let update_time_path = format!("{}/update_time.txt", pending_symlink_directory);
+		let update_time = self.current_timestamp();
+		let update_time = format!("{}", update_time);
+		fs::write(&update_time_path, &update_time).unwrap();
 
-				The `snapshot_sync_day_factors` array is sorted ascendingly, so find() will
-				return on the first iteration that is at least equal to the requested interval.
+		if let Err(error) = self.finalize_directories(cache_path).await {
+			// the previously finalized snapshots may or may not have been replaced at this point
+			log_error!(self.logger, "Failed to finalize snapshots: {}", error);
+			return None;
+		}
 
-				Note, however, that the last value in the array is u64::max, which means that
-				multiplying it with snapshot_interval will overflow. To avoid that, we use
-				saturating_mul.
-				 */
+		let (disk_usage_bytes, inode_count) = measure_tree(cache_path);
+		log_info!(self.logger, "Cache path {} uses {} bytes in {} inodes", cache_path, disk_usage_bytes, inode_count);
 
-				// find min(x) in snapshot_scopes where i * granularity <= x (the current scope)
-				snapshot_scopes.iter().find(|current_scope| {
-					i * granularity_interval <= **current_scope
-				}).unwrap().clone()
-			};
-			log_info!(self.logger, "i: {}, referenced scope: {}", i, referenced_scope);
+		*self.last_full_snapshot_filename.lock().unwrap() = Some(snapshot_filenames_by_scope[&u64::MAX].clone());
+		if full_announcement_count.is_some() {
+			*self.last_full_announcement_count.lock().unwrap() = full_announcement_count;
+		}
 
-			let snapshot_filename = snapshot_filenames_by_scope.get(&referenced_scope).unwrap();
-			let relative_snapshot_path = format!("{}/{}", relative_symlink_to_snapshot_path, snapshot_filename);
+		let cycle_duration = cycle_start.elapsed();
+		log_info!(self.logger, "Finalized snapshots for {} in {:?}", reference_timestamp, cycle_duration);
+		let duration_threshold = Duration::from_secs(snapshot_interval) * self.config.cycle_duration_alert_percent as u32 / 100;
+		let exceeded_duration_threshold = cycle_duration > duration_threshold;
+		if exceeded_duration_threshold {
+			log_error!(self.logger, "Snapshot cycle took {:?}, exceeding {}% of the {}s snapshot interval. Cycles are at risk of overrunning the interval.", cycle_duration, self.config.cycle_duration_alert_percent, snapshot_interval);
+		}
+		let time_to_first_snapshot = self.startup.as_ref().map(|(startup, time_to_first_snapshot)| {
+			*time_to_first_snapshot.lock().unwrap().get_or_insert_with(|| {
+				let elapsed = startup.elapsed();
+				log_info!(self.logger, "First snapshots finalized {:?} after startup", elapsed);
+				elapsed
+			})
+		});
 
-			let canonical_last_sync_timestamp = if i == 0 {
-				// special-case 0 to always refer to a full/initial sync
-				0
-			} else {
-				reference_timestamp.saturating_sub(granularity_interval.saturating_mul(i))
-			};
-			let symlink_path = format!("{}/{}.bin", pending_symlink_directory, canonical_last_sync_timestamp);
+		// the target path is configurable, so make sure the symlinks actually lead somewhere
+		let initial_sync_symlink_path = format!("{}/symlinks/0.bin", cache_path);
+		if let Err(error) = fs::metadata(&initial_sync_symlink_path) {
+			log_warn!(self.logger, "Symlink {} does not resolve to a snapshot file ({}), check the symlink target path {}", initial_sync_symlink_path, error, symlink_to_snapshot_path);
+		}
+		let event = SnapshotEvent {
+			reference_timestamp,
+			format_version: crate::FORMAT_VERSION,
+			scopes: scope_stats,
+			output_dir: cache_path.to_string(),
+			disk_usage_bytes,
+			inode_count,
+			newest_update_timestamp,
+			time_to_first_snapshot,
+			cycle_duration,
+			exceeded_duration_threshold,
+			capture_delay,
+			exceeded_capture_delay_threshold,
+		};
+		// sending only fails if nobody is subscribed, which is fine
+		let _ = self.event_sender.send(event.clone());
 
-			log_info!(self.logger, "Symlinking: {} -> {} ({} -> {}", i, referenced_scope, symlink_path, relative_snapshot_path);
-			symlink(&relative_snapshot_path, &symlink_path).unwrap();
+		if let Some(snapshot_socket) = &self.snapshot_socket {
+			let mut snapshot_socket = snapshot_socket.lock().await;
+			for (scope, data) in &socket_snapshots {
+				snapshot_socket.push(*scope, reference_timestamp, data, self.logger.clone()).await;
+			}
 		}
 
-		let update_time_path = format!("{}/update_time.txt", pending_symlink_directory);
-		let update_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-		fs::write(&update_time_path, format!("{}", update_time)).unwrap();
+		if let Some(command) = &self.config.post_snapshot_command {
+			self.run_post_snapshot_command(command, reference_timestamp, cache_path).await;
+		}
+		if self.config.log_cycle_summary {
+			self.log_cycle_summary(&event);
+		}
+		Some(event)
+	}
+
+	fn newest_update_timestamp(&self) -> Option<u32> {
+		let read_only_graph = self.network_graph.read_only();
+		read_only_graph.channels().unordered_iter()
+			.flat_map(|(_, channel)| [&channel.one_to_two, &channel.two_to_one])
+			.filter_map(|update| update.as_ref().map(|update| update.last_update))
+			.max()
+	}
+
+	/// Log one aligned table row per scope, so a cycle's results can be read at a glance
+	fn log_cycle_summary(&self, event: &SnapshotEvent) {
+		let scope_names: Vec<String> = event.scopes.iter().map(|stats| {
+			if stats.scope == u64::MAX { "full".to_string() } else { format!("{}s", stats.scope) }
+		}).collect();
+		let scope_width = scope_names.iter().map(|name| name.len()).max().unwrap_or(0).max("scope".len());
+		let filename_width = event.scopes.iter().map(|stats| stats.filename.len()).max().unwrap_or(0).max("filename".len());
+
+		log_info!(self.logger, "Snapshot cycle {} summary (format version {}):", event.reference_timestamp, event.format_version);
+		log_info!(self.logger, "{:<scope_width$}  {:<filename_width$}  {:>10}  {:>8}  {:>13}  {:>7}  {:>10}", "scope", "filename", "bytes", "messages", "announcements", "updates", "duration");
+		for (stats, scope_name) in event.scopes.iter().zip(&scope_names) {
+			log_info!(self.logger, "{:<scope_width$}  {:<filename_width$}  {:>10}  {:>8}  {:>13}  {:>7}  {:>10}", scope_name, stats.filename, stats.size_bytes, stats.message_count, stats.announcement_count, stats.update_count, format!("{:.2?}", stats.duration));
+		}
+		log_info!(self.logger, "Total: {} bytes in {} inodes", event.disk_usage_bytes, event.inode_count);
+	}
+
+	/// Write the short channel IDs a snapshot covers to `<snapshot_path>.scids`, as consecutive
+	/// big-endian 64-bit integers in ascending order
+	fn write_scid_index(snapshot_path: &str, data: &[u8]) -> std::io::Result<()> {
+		let short_channel_ids = crate::serialization::read_short_channel_ids(data)
+			.map_err(|error| std::io::Error::new(ErrorKind::InvalidData, format!("failed to read back serialized snapshot: {:?}", error)))?;
+		let index: Vec<u8> = short_channel_ids.iter().flat_map(|short_channel_id| short_channel_id.to_be_bytes()).collect();
+		let index_path = format!("{}.scids", snapshot_path);
+		if let Err(error) = fs::write(&index_path, &index) {
+			// a truncated index would be worse than none
+			let _ = fs::remove_file(&index_path);
+			return Err(error);
+		}
+		Ok(())
+	}
+
+	/// Replace the finalized directories with the pending ones.
+	///
+	/// Each operation is retried with a backoff, because on network filesystems they can fail
+	/// transiently, and failing here would waste an otherwise complete cycle.
+	async fn finalize_directories(&self, cache_path: &str) -> std::io::Result<()> {
+		let pending_snapshot_directory = format!("{}/snapshots_pending", cache_path);
+		let pending_symlink_directory = format!("{}/symlinks_pending", cache_path);
+		let finalized_snapshot_directory = format!("{}/snapshots", cache_path);
+		let finalized_symlink_directory = format!("{}/symlinks", cache_path);
+
+		if self.config.keep_previous_snapshots {
+			// Rather than deleting the current set, move it aside so that it remains servable while
+			// the new set is swapped in. Both directories are kept side by side, so the relative
+			// symlink targets keep resolving within the previous set.
+			let previous_directory = format!("{}/previous", cache_path);
+			self.retry_finalize_operation("remove previous snapshot set", || remove_dir_if_exists(&previous_directory)).await?;
+			self.retry_finalize_operation("create previous snapshot set directory", || fs::create_dir_all(&previous_directory)).await?;
+			let previous_snapshot_directory = format!("{}/snapshots", previous_directory);
+			let previous_symlink_directory = format!("{}/symlinks", previous_directory);
+			self.retry_finalize_operation("retain previous snapshot directory", || rename_if_exists(&finalized_snapshot_directory, &previous_snapshot_directory)).await?;
+			self.retry_finalize_operation("retain previous symlink directory", || rename_if_exists(&finalized_symlink_directory, &previous_symlink_directory)).await?;
+		}
+
+		self.retry_finalize_operation("remove finalized snapshot directory", || remove_dir_if_exists(&finalized_snapshot_directory)).await?;
+		self.retry_finalize_operation("remove finalized symlink directory", || remove_dir_if_exists(&finalized_symlink_directory)).await?;
+		self.retry_finalize_operation("finalize snapshot directory", || fs::rename(&pending_snapshot_directory, &finalized_snapshot_directory)).await?;
+		self.retry_finalize_operation("finalize symlink directory", || fs::rename(&pending_symlink_directory, &finalized_symlink_directory)).await?;
+		Ok(())
+	}
+
+	async fn retry_finalize_operation<F: FnMut() -> std::io::Result<()>>(&self, description: &str, mut operation: F) -> std::io::Result<()> {
+		let mut backoff = FINALIZE_INITIAL_BACKOFF;
+		let mut attempt = 1;
+		loop {
+			match operation() {
+				Ok(()) => return Ok(()),
+				Err(error) if attempt < FINALIZE_ATTEMPTS => {
+					log_warn!(self.logger, "Failed to {} (attempt {}/{}), retrying in {:?}: {}", description, attempt, FINALIZE_ATTEMPTS, backoff, error);
+					tokio::time::sleep(backoff).await;
+					backoff *= 2;
+					attempt += 1;
+				}
+				Err(error) => return Err(std::io::Error::new(error.kind(), format!("failed to {}: {}", description, error))),
+			}
+		}
+	}
 
-		if fs::metadata(&finalized_snapshot_directory).is_ok() {
-			fs::remove_dir_all(&finalized_snapshot_directory).expect("Failed to remove finalized snapshot directory.");
+	/// Run the operator-provided post-snapshot command through the shell, passing it the
+	/// reference timestamp and the cache path as its positional parameters (`$1` and `$2`).
+	///
+	/// Failures are logged, but never fatal, because the snapshots have already been finalized.
+	async fn run_post_snapshot_command(&self, command: &str, reference_timestamp: u64, cache_path: &str) {
+		log_info!(self.logger, "Running post-snapshot command: {}", command);
+		let output = tokio::process::Command::new("sh")
+			.arg("-c")
+			.arg(command)
+			.arg("rgs-post-snapshot")
+			.arg(reference_timestamp.to_string())
+			.arg(cache_path)
+			.output()
+			.await;
+		let output = match output {
+			Ok(output) => output,
+			Err(error) => {
+				log_warn!(self.logger, "Failed to run post-snapshot command: {}", error);
+				return;
+			}
+		};
+		for line in String::from_utf8_lossy(&output.stdout).lines() {
+			log_info!(self.logger, "post-snapshot command stdout: {}", line);
+		}
+		for line in String::from_utf8_lossy(&output.stderr).lines() {
+			log_info!(self.logger, "post-snapshot command stderr: {}", line);
+		}
+		if !output.status.success() {
+			log_warn!(self.logger, "Post-snapshot command exited with {}", output.status);
+		}
+	}
+
+	/// Remove a rejected cycle's pending directories, leaving the previously finalized snapshots in
+	/// place. Cleanup failures are only logged, because the next cycle purges the directories again.
+	fn abandon_cycle(&self, pending_snapshot_directory: &str, pending_symlink_directory: &str) -> Option<SnapshotEvent> {
+		for directory in [pending_snapshot_directory, pending_symlink_directory] {
+			if let Err(error) = remove_dir_if_exists(directory) {
+				log_warn!(self.logger, "Failed to remove abandoned pending directory {}: {}", directory, error);
+			}
+		}
+		None
+	}
+
+	/// Check that a full snapshot can be applied to an empty graph the way a client would, and that
+	/// the client then knows every channel it announces
+	fn self_test_full_snapshot(&self, snapshot: &SerializedResponse, reference_timestamp: u64) -> Result<(), String> {
+		let genesis_hash = self.network_graph.get_genesis_hash();
+		let network = crate::genesis_network(&genesis_hash[..]).ok_or("Network graph is for an unknown chain")?;
+		let client_graph = NetworkGraph::new(network, self.logger.clone());
+		RapidGossipSync::new(&client_graph, self.logger.clone())
+			.update_network_graph_no_std(&snapshot.data, Some(reference_timestamp))
+			.map_err(|error| format!("Failed to apply it: {:?}", error))?;
+		let channel_count = client_graph.read_only().channels().len();
+		if channel_count != snapshot.announcement_count as usize {
+			return Err(format!("It announces {} channels, but applying it yields {}", snapshot.announcement_count, channel_count));
+		}
+		Ok(())
+	}
+
+	/// Copy the last finalized full snapshot `previous_filename`, including all its variants and
+	/// its short channel ID index, into the pending snapshot directory as `filename`
+	fn retain_previous_full_snapshot(&self, cache_path: &str, pending_snapshot_directory: &str, previous_filename: &str, filename: &str, variants: &[SnapshotCompression]) -> std::io::Result<()> {
+		let finalized_path = format!("{}/snapshots/{}", cache_path, previous_filename);
+		let pending_path = format!("{}/{}", pending_snapshot_directory, filename);
+		for variant in variants {
+			let extension = variant.file_extension();
+			fs::copy(format!("{}{}", finalized_path, extension), format!("{}{}", pending_path, extension))?;
+		}
+		// the index is only written if enabled, which may have changed since
+		if fs::metadata(format!("{}.scids", finalized_path)).is_ok() {
+			fs::copy(format!("{}.scids", finalized_path), format!("{}.scids", pending_path))?;
+		}
+		Ok(())
+	}
+
+	/// Write every configured variant of a snapshot file, each with its encoding's extension.
+	///
+	/// Returns the written contents of each variant, in the order of `variants`, so that they can
+	/// be reused without compressing them again.
+	fn write_snapshot_file(snapshot_path: &str, data: &[u8], variants: &[SnapshotCompression]) -> Vec<Vec<u8>> {
+		let mut variant_contents = Vec::with_capacity(variants.len());
+		for variant in variants {
+			let variant_path = format!("{}{}", snapshot_path, variant.file_extension());
+			let variant_data = variant.compress(data);
+			fs::write(&variant_path, &variant_data).unwrap();
+			variant_contents.push(variant_data);
+		}
+		variant_contents
+	}
+
+	fn format_file_sizes(snapshot_filename: &str, variants: &[SnapshotCompression], file_sizes: &[usize]) -> String {
+		variants.iter().zip(file_sizes).map(|(variant, size)| {
+			format!("{}{}: {} bytes", snapshot_filename, variant.file_extension(), size)
+		}).collect::<Vec<_>>().join(", ")
+	}
+
+	/// Create a symlink for every configured variant, pointing at the matching snapshot file.
+	fn create_symlinks(&self, target_path: &str, symlink_path: &str, variants: &[SnapshotCompression]) -> std::io::Result<()> {
+		create_symlinks(target_path, symlink_path, variants, &self.logger)
+	}
+
+	/// Create the `(target path, symlink path)` symlinks for every configured variant across the
+	/// configured number of blocking threads, returning how many of them were created.
+	///
+	/// The symlinks are claimed in order, so if the time budget runs out, exactly the ones at the
+	/// end of the list are skipped, no matter how many threads there are. If any symlink can't be
+	/// created, the remaining ones are skipped and the error is returned.
+	async fn create_symlinks_in_parallel(&self, symlinks: Vec<(String, String)>, variants: &[SnapshotCompression], start: Instant, time_budget: Option<Duration>) -> Result<u64, String> {
+		let symlinks = Arc::new(symlinks);
+		let next_index = Arc::new(AtomicUsize::new(0));
+		let mut workers = Vec::new();
+		for _ in 0..self.config.symlink_threads {
+			let symlinks = Arc::clone(&symlinks);
+			let next_index = Arc::clone(&next_index);
+			let variants = variants.to_vec();
+			let logger = self.logger.clone();
+			workers.push(tokio::task::spawn_blocking(move || {
+				let mut created_count = 0;
+				loop {
+					if let Some(time_budget) = time_budget {
+						if next_index.load(Ordering::Relaxed) > 0 && start.elapsed() > time_budget {
+							break;
+						}
+					}
+					let index = next_index.fetch_add(1, Ordering::Relaxed);
+					let (target_path, symlink_path) = match symlinks.get(index) {
+						Some(symlink) => symlink,
+						None => break,
+					};
+					if let Err(error) = create_symlinks(target_path, symlink_path, &variants, &logger) {
+						// stop the other workers from claiming further symlinks
+						next_index.store(symlinks.len(), Ordering::Relaxed);
+						return Err(error);
+					}
+					created_count += 1;
+				}
+				Ok(created_count)
+			}));
+		}
+		let mut created_count = 0;
+		let mut first_error = None;
+		// every worker is awaited, so that none is still writing once the cycle is abandoned
+		for worker in workers {
+			match worker.await {
+				Ok(Ok(worker_created_count)) => created_count += worker_created_count,
+				Ok(Err(error)) => { first_error.get_or_insert(format!("Failed to create symlink {}", error)); },
+				Err(error) => { first_error.get_or_insert(format!("Symlink creation failed: {}", error)); },
+			}
 		}
-		if fs::metadata(&finalized_symlink_directory).is_ok() {
-			fs::remove_dir_all(&finalized_symlink_directory).expect("Failed to remove pending symlink directory.");
+		match first_error {
+			Some(error) => Err(error),
+			None => Ok(created_count),
 		}
-		fs::rename(&pending_snapshot_directory, &finalized_snapshot_directory).expect("Failed to finalize snapshot directory.");
-		fs::rename(&pending_symlink_directory, &finalized_symlink_directory).expect("Failed to finalize symlink directory.");
 	}
 
 	pub(super) fn round_down_to_nearest_multiple(number: u64, multiple: u64) -> u64 {
@@ -0,0 +1,129 @@
+use std::io::{Error, ErrorKind};
+use std::ops::Deref;
+use std::time::Duration;
+
+use lightning::log_warn;
+use lightning::util::logger::Logger;
+use tokio::io::AsyncWriteExt;
+use tokio::net::UnixStream;
+use tokio::time::timeout;
+
+/// How long connecting to the listener may take, and the least time writing a frame to it may
+/// take, so that a listener that stops reading can't stall snapshot generation
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The slowest rate in bytes per second a listener may read at before writes to it time out, so
+/// that large full snapshots get proportionally longer than [`SOCKET_TIMEOUT`]
+const MIN_LISTENER_THROUGHPUT: u64 = 1024 * 1024;
+
+/// Pushes finalized snapshots to a co-located server listening on a Unix domain socket.
+///
+/// Each snapshot is sent as a single frame, consisting of the big-endian length of the remainder
+/// of the frame (4 bytes), the scope (8 bytes), the reference timestamp (8 bytes), and the
+/// snapshot itself. The full sync's scope is `u64::MAX`. Snapshots too large for the length to
+/// fit are not pushed.
+///
+/// The connection is established lazily and kept open between cycles. If the listener went away
+/// in the meantime, e.g. because it was restarted, a single reconnection is attempted before the
+/// snapshot is given up on. Connections and writes that time out are treated the same way.
+pub(crate) struct SnapshotSocket {
+	path: String,
+	stream: Option<UnixStream>,
+}
+
+impl SnapshotSocket {
+	pub(crate) fn new(path: String) -> Self {
+		Self { path, stream: None }
+	}
+
+	/// Push a snapshot, logging rather than returning failures, because the snapshots have already
+	/// been finalized on disk at this point.
+	pub(crate) async fn push<L: Deref>(&mut self, scope: u64, reference_timestamp: u64, data: &[u8], logger: L) where L::Target: Logger {
+		let frame = match encode_frame(scope, reference_timestamp, data) {
+			Ok(frame) => frame,
+			Err(error) => {
+				log_warn!(logger, "Failed to push {}-second snapshot to {}: {}", scope, self.path, error);
+				return;
+			}
+		};
+		let reconnected = self.stream.is_none();
+		if let Err(error) = self.send(&frame).await {
+			if reconnected {
+				log_warn!(logger, "Failed to push {}-second snapshot to {}: {}", scope, self.path, error);
+				return;
+			}
+			// the existing connection may have been closed by the listener
+			log_warn!(logger, "Lost connection to snapshot socket {}, reconnecting: {}", self.path, error);
+			if let Err(error) = self.send(&frame).await {
+				log_warn!(logger, "Failed to push {}-second snapshot to {}: {}", scope, self.path, error);
+			}
+		}
+	}
+
+	async fn send(&mut self, frame: &[u8]) -> std::io::Result<()> {
+		let stream = match &mut self.stream {
+			Some(stream) => stream,
+			None => {
+				let stream = timeout(SOCKET_TIMEOUT, UnixStream::connect(&self.path)).await
+					.map_err(|_| Error::new(ErrorKind::TimedOut, "timed out connecting"))??;
+				self.stream.insert(stream)
+			}
+		};
+		let result = match timeout(write_timeout(frame.len()), stream.write_all(frame)).await {
+			Ok(result) => result,
+			Err(_) => Err(Error::new(ErrorKind::TimedOut, "timed out writing snapshot")),
+		};
+		if result.is_err() {
+			self.stream = None;
+		}
+		result
+	}
+}
+
+fn write_timeout(frame_length: usize) -> Duration {
+	SOCKET_TIMEOUT + Duration::from_secs(frame_length as u64 / MIN_LISTENER_THROUGHPUT)
+}
+
+/// The length prefix of a frame carrying `data_length` bytes of snapshot data
+fn frame_length(data_length: usize) -> std::io::Result<u32> {
+	(data_length as u64).checked_add(16).and_then(|length| u32::try_from(length).ok())
+		.ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("snapshot of {} bytes exceeds the maximum frame length", data_length)))
+}
+
+pub(crate) fn encode_frame(scope: u64, reference_timestamp: u64, data: &[u8]) -> std::io::Result<Vec<u8>> {
+	let length = frame_length(data.len())?;
+	let mut frame = Vec::with_capacity(20 + data.len());
+	frame.extend_from_slice(&length.to_be_bytes());
+	frame.extend_from_slice(&scope.to_be_bytes());
+	frame.extend_from_slice(&reference_timestamp.to_be_bytes());
+	frame.extend_from_slice(data);
+	Ok(frame)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use super::{encode_frame, frame_length, write_timeout, SOCKET_TIMEOUT};
+
+	#[test]
+	fn test_frame_encoding() {
+		let frame = encode_frame(u64::MAX, 1700000000, &[76, 68, 75, 1]).unwrap();
+		assert_eq!(frame.len(), 24);
+		assert_eq!(frame[..4], [0, 0, 0, 20]);
+		assert_eq!(frame[4..12], [0xff; 8]);
+		assert_eq!(u64::from_be_bytes(frame[12..20].try_into().unwrap()), 1700000000);
+		assert_eq!(frame[20..], [76, 68, 75, 1]);
+	}
+
+	#[test]
+	fn test_frame_limits() {
+		assert_eq!(frame_length(u32::MAX as usize - 16).unwrap(), u32::MAX);
+		assert!(frame_length(u32::MAX as usize - 15).is_err());
+		assert!(frame_length(usize::MAX).is_err());
+
+		// large snapshots get more time than small ones
+		assert_eq!(write_timeout(24), SOCKET_TIMEOUT);
+		assert_eq!(write_timeout(300 * 1024 * 1024), SOCKET_TIMEOUT + Duration::from_secs(300));
+	}
+}
@@ -1,9 +1,9 @@
 //! Multi-module tests that use database fixtures
 
 use std::cell::RefCell;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::{fs, thread};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use bitcoin::{BlockHash, Network};
 use bitcoin::secp256k1::ecdsa::Signature;
 use bitcoin::secp256k1::{Secp256k1, SecretKey};
@@ -13,15 +13,49 @@ use bitcoin::hashes::sha256d::Hash as Sha256dHash;
 use lightning::ln::features::ChannelFeatures;
 use lightning::ln::msgs::{ChannelAnnouncement, ChannelUpdate, UnsignedChannelAnnouncement, UnsignedChannelUpdate};
 use lightning::routing::gossip::{NetworkGraph, NodeId};
-use lightning::util::ser::Writeable;
+use lightning::util::ser::{Readable, Writeable};
 use lightning_rapid_gossip_sync::RapidGossipSync;
-use crate::{config, serialize_delta};
+use tokio::sync::broadcast;
+use crate::{config, content_addressed_filename, content_hash, load_network_graph, serialize_delta, serialize_delta_between, serialize_delta_since, DeltaFilters, snapshot_to_json, serialize_delta_since_block_height, serialize_empty_blob, empty_delta_blob, SerializationConfig, SerializedResponse, GOSSIP_PREFIX};
+use crate::client::{validate_snapshot, UploadCredentials, UploadOutcome, Uploader};
+use crate::compression::SnapshotCompression;
 use crate::persistence::GossipPersister;
-use crate::snapshot::Snapshotter;
+use crate::serialization::{read_serialized_response, read_short_channel_ids};
+use crate::snapshot::{calendar_snapshot_scopes, create_symlinks, CacheLock, SnapshotEvent, symlink_plan, prepare_cache_path, rebuild_symlinks, record_symlink_grid, remove_dangling_symlinks, referenced_scope, verify_served_tree, TreeProblem, resolve_snapshot, scope_last_sync_timestamp, snapshot_filename, snapshot_sync_timestamps, symlink_last_sync_timestamp, Snapshotter, SnapshotterConfig, SnapshotterDependencies};
 use crate::types::{GossipMessage, tests::TestLogger};
 
 const CLIENT_BACKDATE_INTERVAL: u32 = 3600 * 24 * 7; // client backdates RGS by a week
 
+/// Records the timestamp and message count of every upload instead of sending it anywhere
+#[derive(Default)]
+struct RecordingUploader {
+	uploads: Mutex<Vec<(u64, u32)>>,
+	/// The content encodings file uploads were labeled with
+	file_content_encodings: Mutex<Vec<String>>,
+}
+
+impl RecordingUploader {
+	fn record(&self, snapshot: &SerializedResponse, timestamp: u64) -> anyhow::Result<UploadOutcome> {
+		self.uploads.lock().unwrap().push((timestamp, snapshot.message_count));
+		Ok(UploadOutcome { status: 200, id: None, url: None, bytes_sent: snapshot.data.len() })
+	}
+}
+
+impl Uploader for RecordingUploader {
+	fn post_snapshot_ref(&self, snapshot: &SerializedResponse, timestamp: u64, _token: String) -> anyhow::Result<UploadOutcome> {
+		self.record(snapshot, timestamp)
+	}
+
+	fn post_compressed_snapshot(&self, snapshot: &SerializedResponse, _compressed_data: Vec<u8>, _content_encoding: &str, timestamp: u64, _token: String) -> anyhow::Result<UploadOutcome> {
+		self.record(snapshot, timestamp)
+	}
+
+	fn post_snapshot_file(&self, _path: &str, snapshot: SerializedResponse, content_encoding: &str, timestamp: u64, _token: String) -> anyhow::Result<UploadOutcome> {
+		self.file_content_encodings.lock().unwrap().push(content_encoding.to_string());
+		self.record(&snapshot, timestamp)
+	}
+}
+
 thread_local! {
 	static DB_TEST_SCHEMA: RefCell<Option<String>> = RefCell::new(None);
 	static IS_TEST_SCHEMA_CLEAN: RefCell<Option<bool>> = RefCell::new(None);
@@ -47,13 +81,19 @@ pub(crate) fn db_test_schema() -> String {
 }
 
 fn generate_announcement(short_channel_id: u64) -> ChannelAnnouncement {
+	generate_announcement_between(short_channel_id, 1, 2)
+}
+
+/// Generate an announcement for a channel between the nodes whose private keys are filled with
+/// `key_byte_1` and `key_byte_2`
+fn generate_announcement_between(short_channel_id: u64, key_byte_1: u8, key_byte_2: u8) -> ChannelAnnouncement {
 	let secp_context = Secp256k1::new();
 
-	let random_private_key_1 = SecretKey::from_slice(&[1; 32]).unwrap();
+	let random_private_key_1 = SecretKey::from_slice(&[key_byte_1; 32]).unwrap();
 	let random_public_key_1 = random_private_key_1.public_key(&secp_context);
 	let node_id_1 = NodeId::from_pubkey(&random_public_key_1);
 
-	let random_private_key_2 = SecretKey::from_slice(&[2; 32]).unwrap();
+	let random_private_key_2 = SecretKey::from_slice(&[key_byte_2; 32]).unwrap();
 	let random_public_key_2 = random_private_key_2.public_key(&secp_context);
 	let node_id_2 = NodeId::from_pubkey(&random_public_key_2);
 
@@ -172,6 +212,319 @@ async fn clean_test_db() {
 	});
 }
 
+#[test]
+fn test_serialized_response_binary_encoding() {
+	let response = SerializedResponse {
+		content_hash: content_hash(&[76, 68, 75, 1, 42]),
+		data: vec![76, 68, 75, 1, 42],
+		message_count: 7,
+		announcement_count: 3,
+		update_count: 4,
+		update_count_full: 1,
+		update_count_incremental: 3,
+		last_sync_block_height: None,
+	};
+
+	let encoded = response.encode();
+	assert_eq!(encoded.len(), 8 + 5 + 5 * 4);
+
+	let decoded = SerializedResponse::read(&mut &encoded[..]).unwrap();
+	assert_eq!(decoded.data, response.data);
+	assert_eq!(decoded.content_hash, response.content_hash);
+	assert_eq!(decoded.message_count, 7);
+	assert_eq!(decoded.announcement_count, 3);
+	assert_eq!(decoded.update_count, 4);
+	assert_eq!(decoded.update_count_full, 1);
+	assert_eq!(decoded.update_count_incremental, 3);
+
+	// a truncated body must not decode
+	assert!(SerializedResponse::read(&mut &encoded[..10]).is_err());
+}
+
+#[test]
+fn test_content_addressed_filename() {
+	let data = [76, 68, 75, 1, 42];
+	let filename = content_addressed_filename(&data, config::hash_algorithm());
+	// the algorithm is part of the name, so that clients know how to verify it
+	assert_eq!(filename, format!("snapshot-{}-{}.lngossip", config::hash_algorithm().name(), &content_hash(&data)[..32]));
+	assert_ne!(filename, content_addressed_filename(&data[..4], config::hash_algorithm()));
+}
+
+#[test]
+fn test_upload_validation() {
+	let empty_blob = serialize_empty_blob(1_700_000_000, config::symlink_granularity_interval() as u64, genesis_hash());
+	let snapshot = |data: Vec<u8>, announcement_count: u32, update_count: u32| SerializedResponse {
+		content_hash: content_hash(&data),
+		data,
+		message_count: announcement_count + update_count,
+		announcement_count,
+		update_count,
+		update_count_full: update_count,
+		update_count_incremental: 0,
+		last_sync_block_height: None,
+	};
+
+	assert!(validate_snapshot(&snapshot(empty_blob.clone(), 0, 0)).is_ok());
+	assert!(validate_snapshot(&snapshot(vec![], 0, 0)).is_err());
+	assert!(validate_snapshot(&snapshot(empty_blob[..51].to_vec(), 0, 0)).is_err());
+	// far too short for a thousand updates
+	assert!(validate_snapshot(&snapshot(empty_blob.clone(), 0, 1000)).is_err());
+
+	let mut inconsistent = snapshot(empty_blob, 0, 0);
+	inconsistent.message_count = 1;
+	assert!(validate_snapshot(&inconsistent).is_err());
+}
+
+#[test]
+fn test_prepare_cache_path() {
+	let root = std::env::temp_dir().join(format!("rgs-test-prepare-cache-path-{}", std::process::id()));
+	let cache_path = root.join("nested/cache").to_string_lossy().to_string();
+	assert!(fs::metadata(&root).is_err());
+
+	prepare_cache_path(&cache_path).unwrap();
+	assert!(fs::metadata(&cache_path).unwrap().is_dir());
+	// the writability probe must not be left behind
+	assert_eq!(fs::read_dir(&cache_path).unwrap().count(), 0);
+	// preparing an existing cache path is a no-op
+	prepare_cache_path(&cache_path).unwrap();
+
+	// a file in the way can't be turned into a directory
+	let file_path = root.join("file");
+	fs::write(&file_path, []).unwrap();
+	assert!(prepare_cache_path(&file_path.to_string_lossy()).is_err());
+
+	fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_network_graph_loading() {
+	let logger = Arc::new(TestLogger::with_id("test_network_graph_loading".to_string()));
+	let network_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
+	network_graph.update_channel_from_announcement_no_lookup(&generate_announcement(1)).unwrap();
+	let path = std::env::temp_dir().join(format!("rgs-test-network-graph-{}.bin", std::process::id())).to_string_lossy().to_string();
+	fs::write(&path, network_graph.encode()).unwrap();
+
+	let loaded_graph = load_network_graph(&path, Network::Bitcoin, logger.clone()).unwrap();
+	assert_eq!(loaded_graph.read_only().channels().len(), 1);
+	assert!(loaded_graph.read_only().channels().get(&1).is_some());
+	assert!(load_network_graph(&path, Network::Testnet, logger.clone()).is_err());
+
+	fs::write(&path, [0u8; 3]).unwrap();
+	assert!(load_network_graph(&path, Network::Bitcoin, logger.clone()).is_err());
+	fs::remove_file(&path).unwrap();
+	assert!(load_network_graph(&path, Network::Bitcoin, logger).is_err());
+}
+
+#[test]
+fn test_dangling_symlink_removal() {
+	let logger = Arc::new(TestLogger::with_id("test_dangling_symlink_removal".to_string()));
+	let root = std::env::temp_dir().join(format!("rgs-test-dangling-symlinks-{}", std::process::id()));
+	let symlink_directory = root.join("symlinks");
+	let snapshot_directory = root.join("snapshots");
+	fs::create_dir_all(&symlink_directory).unwrap();
+	fs::create_dir_all(&snapshot_directory).unwrap();
+	fs::write(snapshot_directory.join("snapshot.lngossip"), [76, 68, 75, 1]).unwrap();
+	std::os::unix::fs::symlink("../snapshots/snapshot.lngossip", symlink_directory.join("0.bin")).unwrap();
+	std::os::unix::fs::symlink("../snapshots/missing.lngossip", symlink_directory.join("3600.bin")).unwrap();
+
+	let symlink_directory = symlink_directory.to_string_lossy().to_string();
+	assert_eq!(remove_dangling_symlinks(&symlink_directory, &logger), 1);
+	assert!(fs::symlink_metadata(format!("{}/3600.bin", symlink_directory)).is_err());
+	assert_eq!(fs::read(format!("{}/0.bin", symlink_directory)).unwrap(), [76, 68, 75, 1]);
+	assert_eq!(remove_dangling_symlinks(&symlink_directory, &logger), 0);
+
+	fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_symlink_creation_failure() {
+	let logger = Arc::new(TestLogger::with_id("test_symlink_creation_failure".to_string()));
+	let root = std::env::temp_dir().join(format!("rgs-test-symlink-failure-{}", std::process::id()));
+	fs::create_dir_all(&root).unwrap();
+	let root_path = root.to_string_lossy().to_string();
+
+	// an existing entry is replaced
+	fs::write(root.join("0.bin"), b"stale").unwrap();
+	create_symlinks("../snapshots/snapshot.lngossip", &format!("{}/0.bin", root_path), &[SnapshotCompression::Identity], &logger).unwrap();
+	assert_eq!(fs::read_link(root.join("0.bin")).unwrap().to_string_lossy(), "../snapshots/snapshot.lngossip");
+
+	// a missing directory is reported rather than panicking, naming the symlink
+	let missing_symlink_path = format!("{}/missing/0.bin", root_path);
+	let error = create_symlinks("../snapshots/snapshot.lngossip", &missing_symlink_path, &[SnapshotCompression::Identity], &logger).unwrap_err();
+	assert!(error.to_string().starts_with(&missing_symlink_path));
+
+	fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_scope_timestamp_arithmetic() {
+	let reference_timestamp = 1_700_000_000;
+
+	// the full sync
+	assert_eq!(scope_last_sync_timestamp(reference_timestamp, u64::MAX), 0);
+	// the largest finite scope
+	let max_scope = config::MAX_SNAPSHOT_SCOPE as u64;
+	assert_eq!(scope_last_sync_timestamp(reference_timestamp, max_scope), reference_timestamp - max_scope);
+	// scopes reaching back exactly to or past the epoch
+	assert_eq!(scope_last_sync_timestamp(reference_timestamp, reference_timestamp), 0);
+	assert_eq!(scope_last_sync_timestamp(reference_timestamp, reference_timestamp + 1), 0);
+
+	assert_eq!(symlink_last_sync_timestamp(reference_timestamp, 3600, 0), reference_timestamp);
+	assert_eq!(symlink_last_sync_timestamp(reference_timestamp, 3600, 2), reference_timestamp - 7200);
+	assert_eq!(symlink_last_sync_timestamp(reference_timestamp, 3600, u64::MAX), 0);
+}
+
+#[test]
+fn test_calendar_snapshot_scopes() {
+	let day = 24 * 3600;
+	let week = 7 * day;
+	assert_eq!(calendar_snapshot_scopes(6, 3), vec![day, 2 * day, 3 * day, 4 * day, 5 * day, 6 * day, week, 2 * week, 3 * week, u64::MAX]);
+
+	// weekly scopes start past the largest daily one
+	let scopes = calendar_snapshot_scopes(13, 4);
+	assert_eq!(scopes.len(), 18);
+	assert_eq!(scopes[12..], [13 * day, 2 * week, 3 * week, 4 * week, 5 * week, u64::MAX]);
+	let scopes = calendar_snapshot_scopes(7, 1);
+	assert_eq!(scopes[6..], [week, 2 * week, u64::MAX]);
+
+	assert_eq!(calendar_snapshot_scopes(0, 0), vec![u64::MAX]);
+	assert_eq!(calendar_snapshot_scopes(0, 2), vec![week, 2 * week, u64::MAX]);
+}
+
+#[test]
+fn test_scope_resolution() {
+	let granularity_interval = 100;
+	let snapshot_scopes = [100, 200, 400, 800, u64::MAX];
+	let resolve = |symlink_index| referenced_scope(symlink_index, granularity_interval, &snapshot_scopes, u64::MAX);
+
+	// timestamp 0 is always served the initial sync
+	assert_eq!(resolve(0), Some(u64::MAX));
+	assert_eq!(referenced_scope(0, granularity_interval, &snapshot_scopes, 800), Some(800));
+
+	// exactly at a scope
+	assert_eq!(resolve(1), Some(100));
+	assert_eq!(resolve(2), Some(200));
+	assert_eq!(resolve(4), Some(400));
+	assert_eq!(resolve(8), Some(800));
+
+	// just past a scope, falling through to the next larger one
+	assert_eq!(resolve(3), Some(400));
+	assert_eq!(resolve(5), Some(800));
+	assert_eq!(resolve(9), Some(u64::MAX));
+
+	// beyond the largest limited scope, without overflowing
+	assert_eq!(resolve(u64::MAX / 2), Some(u64::MAX));
+	assert_eq!(resolve(u64::MAX), Some(u64::MAX));
+
+	// without the full sync, nothing is served beyond the largest scope
+	assert_eq!(referenced_scope(8, granularity_interval, &snapshot_scopes[..4], u64::MAX), Some(800));
+	assert_eq!(referenced_scope(9, granularity_interval, &snapshot_scopes[..4], u64::MAX), None);
+
+	let reference_timestamp = 1_000_000;
+	assert_eq!(snapshot_sync_timestamps(reference_timestamp, &snapshot_scopes), vec![
+		(100, reference_timestamp - 100),
+		(200, reference_timestamp - 200),
+		(400, reference_timestamp - 400),
+		(800, reference_timestamp - 800),
+		(u64::MAX, 0),
+	]);
+
+	assert_eq!(resolve_snapshot(0, reference_timestamp, granularity_interval, &snapshot_scopes, u64::MAX), Some((u64::MAX, snapshot_filename(reference_timestamp, u64::MAX, 0))));
+	assert_eq!(resolve_snapshot(reference_timestamp - 300, reference_timestamp, granularity_interval, &snapshot_scopes, u64::MAX), Some((400, snapshot_filename(reference_timestamp, 400, reference_timestamp - 400))));
+	// timestamps without a symlink
+	assert_eq!(resolve_snapshot(reference_timestamp - 150, reference_timestamp, granularity_interval, &snapshot_scopes, u64::MAX), None);
+	assert_eq!(resolve_snapshot(reference_timestamp, reference_timestamp, granularity_interval, &snapshot_scopes, u64::MAX), None);
+	assert_eq!(resolve_snapshot(reference_timestamp + 100, reference_timestamp, granularity_interval, &snapshot_scopes, u64::MAX), None);
+
+	// the planned symlinks agree with the resolution of their timestamps
+	let reference_timestamp = 1_700_000_000;
+	let plan = symlink_plan(reference_timestamp, granularity_interval, config::SYMLINK_ANCHOR_TIMESTAMP, &snapshot_scopes, u64::MAX, Some(12));
+	assert_eq!(plan.len(), 12);
+	assert_eq!(plan[0], ("0.bin".to_string(), snapshot_filename(reference_timestamp, u64::MAX, 0)));
+	assert_eq!(plan[3].0, format!("{}.bin", reference_timestamp - 300));
+	for (symlink_name, target_filename) in &plan {
+		let timestamp = symlink_name.strip_suffix(".bin").unwrap().parse::<u64>().unwrap();
+		let (_, resolved_filename) = resolve_snapshot(timestamp, reference_timestamp, granularity_interval, &snapshot_scopes, u64::MAX).unwrap();
+		assert_eq!(&resolved_filename, target_filename);
+	}
+	// without a limit, symlinks reach back to the start of 2022
+	assert_eq!(symlink_plan(1_640_995_200 + 1000, granularity_interval, config::SYMLINK_ANCHOR_TIMESTAMP, &snapshot_scopes, u64::MAX, None).len(), 10);
+}
+
+#[test]
+fn test_symlink_grid_stability() {
+	let snapshot_scopes = [86400, u64::MAX];
+	let granularity_interval = 3600;
+	let reference_timestamp = 1_700_006_400;
+	let timestamps = |reference_timestamp: u64| -> Vec<u64> {
+		symlink_plan(reference_timestamp, granularity_interval, config::SYMLINK_ANCHOR_TIMESTAMP, &snapshot_scopes, u64::MAX, None).into_iter()
+			.map(|(symlink_name, _)| symlink_name.strip_suffix(".bin").unwrap().parse::<u64>().unwrap())
+			.collect()
+	};
+	// every timestamp served in one cycle is still served in the next
+	let current_timestamps = timestamps(reference_timestamp);
+	let next_timestamps = timestamps(reference_timestamp + 3 * granularity_interval);
+	assert!(current_timestamps.iter().all(|timestamp| next_timestamps.contains(timestamp)));
+	assert!(current_timestamps.iter().all(|timestamp| *timestamp == 0 || (*timestamp > config::SYMLINK_ANCHOR_TIMESTAMP && timestamp % granularity_interval == 0)));
+
+	let logger = Arc::new(TestLogger::with_id("test_symlink_grid_stability".to_string()));
+	let cache_path = std::env::temp_dir().join(format!("rgs-test-symlink-grid-{}", std::process::id()));
+	fs::create_dir_all(&cache_path).unwrap();
+	let cache_path = cache_path.to_string_lossy().to_string();
+	assert!(!record_symlink_grid(&cache_path, 1_640_995_200, 3600, &logger));
+	assert!(!record_symlink_grid(&cache_path, 1_640_995_200, 3600, &logger));
+	// a finer grid keeps the previous timestamps, but a coarser one doesn't
+	assert!(!record_symlink_grid(&cache_path, 1_640_995_200, 1800, &logger));
+	assert!(record_symlink_grid(&cache_path, 1_640_995_200, 7200, &logger));
+	assert!(record_symlink_grid(&cache_path, 1_641_024_000, 7200, &logger));
+	fs::remove_dir_all(&cache_path).unwrap();
+}
+
+#[test]
+fn test_cache_lock_exclusion() {
+	let cache_path = std::env::temp_dir().join(format!("rgs-test-cache-lock-{}", std::process::id()));
+	let cache_path = cache_path.to_string_lossy().to_string();
+	let cache_lock = CacheLock::acquire(&cache_path).unwrap();
+	// locks belong to open files, so a second acquisition fails even within the same process
+	let error = CacheLock::acquire(&cache_path).err().unwrap();
+	assert!(error.contains(&format!("PID {}", std::process::id())));
+	drop(cache_lock);
+	assert!(CacheLock::acquire(&cache_path).is_ok());
+	fs::remove_dir_all(&cache_path).unwrap();
+}
+
+#[test]
+fn test_empty_blob_is_noop() {
+	let logger = Arc::new(TestLogger::with_id("test_empty_blob_is_noop".to_string()));
+	let reference_timestamp = 1_700_000_000;
+	let chain_hash = bitcoin::blockdata::constants::genesis_block(config::network()).block_hash();
+	let blob = serialize_empty_blob(reference_timestamp, config::symlink_granularity_interval() as u64, chain_hash);
+
+	// prefix, chain hash, timestamp, and the three zero counts
+	assert_eq!(blob.len(), 4 + 32 + 4 + 3 * 4);
+	// the dummy must always carry the current format version
+	assert_eq!(&blob[..4], &GOSSIP_PREFIX);
+	assert_eq!(&blob[4..36], &chain_hash[..]);
+	assert_eq!(empty_delta_blob(config::network(), reference_timestamp), blob);
+
+	// a blob for another chain must be rejected
+	let testnet_chain_hash = bitcoin::blockdata::constants::genesis_block(Network::Testnet).block_hash();
+	let testnet_blob = serialize_empty_blob(reference_timestamp, config::symlink_granularity_interval() as u64, testnet_chain_hash);
+	assert_eq!(&testnet_blob[4..36], &testnet_chain_hash[..]);
+	let mainnet_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
+	assert!(RapidGossipSync::new(&mainnet_graph, logger.clone()).update_network_graph_no_std(&testnet_blob, Some(reference_timestamp)).is_err());
+
+	let client_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
+	let client_graph_arc = Arc::new(client_graph);
+	let rgs = RapidGossipSync::new(client_graph_arc.clone(), logger.clone());
+	let update_result = rgs.update_network_graph_no_std(&blob, Some(reference_timestamp)).unwrap();
+	assert_eq!(update_result as u64, reference_timestamp - reference_timestamp % config::SYMLINK_GRANULARITY_INTERVAL as u64);
+
+	let readonly_graph = client_graph_arc.read_only();
+	assert!(readonly_graph.channels().is_empty());
+	assert!(readonly_graph.nodes().is_empty());
+}
+
 #[tokio::test]
 async fn test_trivial_setup() {
 	let _sanitizer = SchemaSanitizer::new();
@@ -200,7 +553,7 @@ async fn test_trivial_setup() {
 		persister.persist_gossip().await;
 	}
 
-	let serialization = serialize_delta(network_graph_arc.clone(), 0, logger.clone()).await;
+	let serialization = serialize_delta(network_graph_arc.clone(), 0, &SerializationConfig::from_config(), logger.clone()).await.unwrap();
 	logger.assert_log_contains("rapid_gossip_sync_server", "announcement channel count: 1", 1);
 	clean_test_db().await;
 
@@ -241,6 +594,180 @@ async fn test_trivial_setup() {
 	assert_eq!(last_update_seen_b, update_result - CLIENT_BACKDATE_INTERVAL);
 }
 
+/// Seed the db with a single channel updated in both directions ten seconds ago, and serialize a
+/// full sync of it
+async fn serialize_single_channel(short_channel_id: u64, logger: Arc<TestLogger>) -> SerializedResponse {
+	let network_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
+	let network_graph_arc = Arc::new(network_graph);
+	let (mut persister, receiver) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+	let timestamp = current_time() - 10;
+
+	let announcement = generate_announcement(short_channel_id);
+	let update_1 = generate_update(short_channel_id, false, timestamp, 0, 0, 0, 5, 0);
+	let update_2 = generate_update(short_channel_id, true, timestamp, 0, 0, 0, 10, 0);
+
+	network_graph_arc.update_channel_from_announcement_no_lookup(&announcement).unwrap();
+	network_graph_arc.update_channel_unsigned(&update_1.contents).unwrap();
+	network_graph_arc.update_channel_unsigned(&update_2.contents).unwrap();
+
+	receiver.send(GossipMessage::ChannelAnnouncement(announcement, None)).await.unwrap();
+	receiver.send(GossipMessage::ChannelUpdate(update_1, None)).await.unwrap();
+	receiver.send(GossipMessage::ChannelUpdate(update_2, None)).await.unwrap();
+	drop(receiver);
+	persister.persist_gossip().await;
+
+	serialize_delta(network_graph_arc, 0, &SerializationConfig::from_config(), logger).await.unwrap()
+}
+
+#[tokio::test]
+async fn test_empty_blob_header() {
+	let _sanitizer = SchemaSanitizer::new();
+	let logger = Arc::new(TestLogger::new());
+	let serialization = serialize_single_channel(1, logger).await;
+	clean_test_db().await;
+
+	// empty blobs must start exactly like real snapshots
+	let serialized_timestamp = u32::from_be_bytes(serialization.data[36..40].try_into().unwrap());
+	let empty_blob = serialize_empty_blob(serialized_timestamp as u64, config::symlink_granularity_interval() as u64, genesis_hash());
+	assert_eq!(empty_blob[..40], serialization.data[..40]);
+}
+
+#[tokio::test]
+async fn test_read_serialized_response() {
+	let _sanitizer = SchemaSanitizer::new();
+	let logger = Arc::new(TestLogger::new());
+	let short_channel_id = 1;
+	let serialization = serialize_single_channel(short_channel_id, logger).await;
+	clean_test_db().await;
+
+	let reread = read_serialized_response(serialization.data.clone()).unwrap();
+	assert_eq!(reread.message_count, serialization.message_count);
+	assert_eq!(reread.announcement_count, serialization.announcement_count);
+	assert_eq!(reread.update_count_full, serialization.update_count_full);
+	assert_eq!(reread.update_count_incremental, serialization.update_count_incremental);
+	assert_eq!(reread.content_hash, serialization.content_hash);
+	assert_eq!(read_short_channel_ids(&serialization.data).unwrap(), vec![short_channel_id]);
+}
+
+#[tokio::test]
+async fn test_snapshot_to_json() {
+	let _sanitizer = SchemaSanitizer::new();
+	let logger = Arc::new(TestLogger::new());
+	let short_channel_id = 1;
+	let serialization = serialize_single_channel(short_channel_id, logger).await;
+	clean_test_db().await;
+
+	let json = snapshot_to_json(&serialization.data, false).unwrap();
+	let json_channels = json["channels"].as_array().unwrap();
+	assert_eq!(json_channels.len(), 1);
+	assert_eq!(json_channels[0]["short_channel_id"], short_channel_id);
+	let json_updates = json["updates"].as_array().unwrap();
+	assert_eq!(json_updates.len(), 2);
+	assert_eq!(json_updates[0]["direction"], "one_to_two");
+	assert_eq!(json_updates[0]["fee_base_msat"], 5);
+	assert_eq!(json_updates[1]["direction"], "two_to_one");
+	assert_eq!(json_updates[1]["fee_base_msat"], 10);
+}
+
+#[tokio::test]
+async fn test_delta_snapshot_to_json() {
+	let _sanitizer = SchemaSanitizer::new();
+	let logger = Arc::new(TestLogger::new());
+	let network_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
+	let network_graph_arc = Arc::new(network_graph);
+
+	let short_channel_id = 1;
+	let timestamp = current_time();
+
+	{ // seed the db
+		let (mut persister, receiver) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+		let announcement = generate_announcement(short_channel_id);
+		network_graph_arc.update_channel_from_announcement_no_lookup(&announcement).unwrap();
+		receiver.send(GossipMessage::ChannelAnnouncement(announcement, None)).await.unwrap();
+
+		for (offset, fee_rate) in [(60, 1), (10, 2)] {
+			let update = generate_update(short_channel_id, false, timestamp - offset, 0, 0, 0, 0, fee_rate);
+			network_graph_arc.update_channel_unsigned(&update.contents).unwrap();
+			receiver.send(GossipMessage::ChannelUpdate(update, Some(timestamp - offset))).await.unwrap();
+		}
+
+		drop(receiver);
+		persister.persist_gossip().await;
+
+		// announcements are always persisted as seen now
+		let client = crate::connect_to_db().await;
+		let seen = (timestamp - 60) as f64;
+		client.execute("UPDATE channel_announcements SET seen = TO_TIMESTAMP($1) WHERE short_channel_id = $2", &[&seen, &(short_channel_id as i64)]).await.unwrap();
+	}
+
+	// the delta only updates a channel it doesn't announce, which is still listed
+	let serialization = serialize_delta(network_graph_arc.clone(), timestamp - 30, &SerializationConfig::from_config(), logger.clone()).await.unwrap();
+	clean_test_db().await;
+	assert_eq!(serialization.announcement_count, 0);
+
+	let json = snapshot_to_json(&serialization.data, false).unwrap();
+	assert_eq!(json["channels"].as_array().unwrap().len(), 0);
+	let json_updates = json["updates"].as_array().unwrap();
+	assert_eq!(json_updates.len(), 1);
+	assert_eq!(json_updates[0]["short_channel_id"], short_channel_id);
+	assert_eq!(json_updates[0]["incremental"], true);
+	assert_eq!(json_updates[0]["fee_proportional_millionths"], 2);
+	// unchanged properties aren't carried by incremental updates
+	assert!(json_updates[0].get("fee_base_msat").is_none());
+}
+
+#[tokio::test]
+async fn test_block_height_delta() {
+	let _sanitizer = SchemaSanitizer::new();
+	let logger = Arc::new(TestLogger::new());
+	let network_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
+	let network_graph_arc = Arc::new(network_graph);
+	let (mut persister, receiver) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+
+	let old_channel_id = 700_000 << 40;
+	let new_channel_id = (800_000 << 40) | 1;
+	let timestamp = current_time() - 10;
+
+	{ // seed the db
+		for short_channel_id in [old_channel_id, new_channel_id] {
+			let announcement = generate_announcement(short_channel_id);
+			let update_1 = generate_update(short_channel_id, false, timestamp, 0, 0, 0, 5, 0);
+			let update_2 = generate_update(short_channel_id, true, timestamp, 0, 0, 0, 10, 0);
+
+			network_graph_arc.update_channel_from_announcement_no_lookup(&announcement).unwrap();
+			network_graph_arc.update_channel_unsigned(&update_1.contents).unwrap();
+			network_graph_arc.update_channel_unsigned(&update_2.contents).unwrap();
+
+			receiver.send(GossipMessage::ChannelAnnouncement(announcement, None)).await.unwrap();
+			receiver.send(GossipMessage::ChannelUpdate(update_1, None)).await.unwrap();
+			receiver.send(GossipMessage::ChannelUpdate(update_2, None)).await.unwrap();
+		}
+		drop(receiver);
+		persister.persist_gossip().await;
+	}
+
+	let serialization = serialize_delta_since_block_height(network_graph_arc.clone(), 750_000, logger.clone()).await.unwrap();
+	logger.assert_log_contains("rapid_gossip_sync_server::lookup", "Excluded 1 channels confirmed at or before block 750000", 1);
+	clean_test_db().await;
+
+	assert_eq!(serialization.message_count, 3);
+	assert_eq!(serialization.announcement_count, 1);
+	assert_eq!(serialization.update_count, 2);
+	assert_eq!(serialization.update_count_full, 2);
+	// the format version is shared with timestamp deltas, so only the response marks the mode
+	assert_eq!(serialization.last_sync_block_height, Some(750_000));
+
+	let client_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
+	let client_graph_arc = Arc::new(client_graph);
+	let rgs = RapidGossipSync::new(client_graph_arc.clone(), logger.clone());
+	rgs.update_network_graph(&serialization.data).unwrap();
+
+	let readonly_graph = client_graph_arc.read_only();
+	let channels = readonly_graph.channels();
+	assert_eq!(channels.len(), 1);
+	assert!(channels.get(&new_channel_id).is_some());
+}
+
 #[tokio::test]
 async fn test_full_snapshot_recency() {
 	let _sanitizer = SchemaSanitizer::new();
@@ -286,7 +813,7 @@ async fn test_full_snapshot_recency() {
 	let client_graph_arc = Arc::new(client_graph);
 
 	{ // sync after initial seed
-		let serialization = serialize_delta(network_graph_arc.clone(), 0, logger.clone()).await;
+		let serialization = serialize_delta(network_graph_arc.clone(), 0, &SerializationConfig::from_config(), logger.clone()).await.unwrap();
 		logger.assert_log_contains("rapid_gossip_sync_server", "announcement channel count: 1", 1);
 
 		let channel_count = network_graph_arc.read_only().channels().len();
@@ -317,6 +844,173 @@ async fn test_full_snapshot_recency() {
 	clean_test_db().await;
 }
 
+#[tokio::test]
+async fn test_delta_update_deduplication() {
+	let _sanitizer = SchemaSanitizer::new();
+	let logger = Arc::new(TestLogger::new());
+	let network_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
+	let network_graph_arc = Arc::new(network_graph);
+
+	let short_channel_id = 1;
+	let timestamp = current_time();
+
+	{ // seed the db
+		let (mut persister, receiver) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+		let announcement = generate_announcement(short_channel_id);
+		network_graph_arc.update_channel_from_announcement_no_lookup(&announcement).unwrap();
+		receiver.send(GossipMessage::ChannelAnnouncement(announcement, None)).await.unwrap();
+
+		{ // direction false, updated three times within the scope
+			for (offset, fee_rate) in [(30, 1), (20, 2), (10, 3)] {
+				let update = generate_update(short_channel_id, false, timestamp - offset, 0, 0, 0, 0, fee_rate);
+				network_graph_arc.update_channel_unsigned(&update.contents).unwrap();
+				receiver.send(GossipMessage::ChannelUpdate(update, Some(timestamp - offset))).await.unwrap();
+			}
+		}
+		{ // direction true
+			let update = generate_update(short_channel_id, true, timestamp - 30, 0, 0, 0, 0, 10);
+			network_graph_arc.update_channel_unsigned(&update.contents).unwrap();
+			receiver.send(GossipMessage::ChannelUpdate(update, Some(timestamp - 30))).await.unwrap();
+		}
+
+		drop(receiver);
+		persister.persist_gossip().await;
+	}
+
+	let serialization = serialize_delta(network_graph_arc.clone(), timestamp - 60, &SerializationConfig::from_config(), logger.clone()).await.unwrap();
+	// only the latest update per direction is serialized
+	assert_eq!(serialization.announcement_count, 1);
+	assert_eq!(serialization.update_count, 2);
+	assert_eq!(serialization.message_count, 3);
+	let exact_serialization = serialize_delta_since(network_graph_arc.clone(), timestamp - 60, DeltaFilters::default(), logger.clone()).await.unwrap();
+	assert_eq!(exact_serialization.announcement_count, serialization.announcement_count);
+	assert_eq!(exact_serialization.update_count, serialization.update_count);
+	assert_eq!(exact_serialization.data.len(), serialization.data.len());
+
+	let client_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
+	let client_graph_arc = Arc::new(client_graph);
+	let rgs = RapidGossipSync::new(client_graph_arc.clone(), logger.clone());
+	rgs.update_network_graph(&serialization.data).unwrap();
+
+	let readonly_graph = client_graph_arc.read_only();
+	let first_channel = readonly_graph.channels().get(&short_channel_id).unwrap();
+	assert_eq!(first_channel.one_to_two.as_ref().unwrap().fees.proportional_millionths, 3);
+	assert_eq!(first_channel.two_to_one.as_ref().unwrap().fees.proportional_millionths, 10);
+
+	clean_test_db().await;
+}
+
+#[tokio::test]
+async fn test_delta_window() {
+	let _sanitizer = SchemaSanitizer::new();
+	let logger = Arc::new(TestLogger::new());
+	let network_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
+	let network_graph_arc = Arc::new(network_graph);
+
+	let timestamp = current_time();
+
+	{ // seed the db
+		let (mut persister, receiver) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+		for short_channel_id in [1, 2] {
+			let announcement = generate_announcement(short_channel_id);
+			network_graph_arc.update_channel_from_announcement_no_lookup(&announcement).unwrap();
+			receiver.send(GossipMessage::ChannelAnnouncement(announcement, None)).await.unwrap();
+		}
+
+		for (offset, fee_rate) in [(30, 1), (20, 2), (10, 3)] {
+			let update = generate_update(1, false, timestamp - offset, 0, 0, 0, 0, fee_rate);
+			network_graph_arc.update_channel_unsigned(&update.contents).unwrap();
+			receiver.send(GossipMessage::ChannelUpdate(update, Some(timestamp - offset))).await.unwrap();
+		}
+		for short_channel_id in [1, 2] {
+			let update = generate_update(short_channel_id, true, timestamp - 5, 0, 0, 0, 0, 10);
+			network_graph_arc.update_channel_unsigned(&update.contents).unwrap();
+			receiver.send(GossipMessage::ChannelUpdate(update, Some(timestamp - 5))).await.unwrap();
+		}
+
+		drop(receiver);
+		persister.persist_gossip().await;
+
+		// announcements are always persisted as seen now
+		let client = crate::connect_to_db().await;
+		for (short_channel_id, announcement_offset) in [(1i64, 40), (2, 5)] {
+			let seen = (timestamp - announcement_offset) as f64;
+			client.execute("UPDATE channel_announcements SET seen = TO_TIMESTAMP($1) WHERE short_channel_id = $2", &[&seen, &short_channel_id]).await.unwrap();
+		}
+	}
+
+	// neither the second channel nor the updates after the window are included
+	let serialization = serialize_delta_between(network_graph_arc.clone(), timestamp - 60, timestamp - 15, DeltaFilters::default(), logger.clone()).await.unwrap();
+	assert_eq!(serialization.announcement_count, 1);
+	assert_eq!(serialization.update_count, 1);
+
+	let client_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
+	let client_graph_arc = Arc::new(client_graph);
+	let rgs = RapidGossipSync::new(client_graph_arc.clone(), logger.clone());
+	rgs.update_network_graph(&serialization.data).unwrap();
+
+	let readonly_graph = client_graph_arc.read_only();
+	assert_eq!(readonly_graph.channels().len(), 1);
+	let first_channel = readonly_graph.channels().get(&1).unwrap();
+	assert_eq!(first_channel.one_to_two.as_ref().unwrap().fees.proportional_millionths, 2);
+	assert!(first_channel.two_to_one.is_none());
+
+	// a window extending to the present is the same as a delta since its start
+	let serialization = serialize_delta_between(network_graph_arc.clone(), timestamp - 60, timestamp, DeltaFilters::default(), logger.clone()).await.unwrap();
+	let open_serialization = serialize_delta(network_graph_arc.clone(), timestamp - 60, &SerializationConfig::from_config(), logger.clone()).await.unwrap();
+	assert_eq!(serialization.announcement_count, open_serialization.announcement_count);
+	assert_eq!(serialization.update_count, open_serialization.update_count);
+
+	clean_test_db().await;
+}
+
+#[tokio::test]
+async fn test_delta_node_filter() {
+	let _sanitizer = SchemaSanitizer::new();
+	let logger = Arc::new(TestLogger::new());
+	let network_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
+	let network_graph_arc = Arc::new(network_graph);
+
+	let timestamp = current_time() - 10;
+
+	{ // seed the db with two channels that share no endpoint
+		let (mut persister, receiver) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+		for (short_channel_id, key_byte_1, key_byte_2) in [(1, 1, 2), (2, 3, 4)] {
+			let announcement = generate_announcement_between(short_channel_id, key_byte_1, key_byte_2);
+			network_graph_arc.update_channel_from_announcement_no_lookup(&announcement).unwrap();
+			receiver.send(GossipMessage::ChannelAnnouncement(announcement, None)).await.unwrap();
+
+			for direction in [false, true] {
+				let update = generate_update(short_channel_id, direction, timestamp, 0, 0, 0, 0, 10);
+				network_graph_arc.update_channel_unsigned(&update.contents).unwrap();
+				receiver.send(GossipMessage::ChannelUpdate(update, None)).await.unwrap();
+			}
+		}
+
+		drop(receiver);
+		persister.persist_gossip().await;
+	}
+
+	let secp_context = Secp256k1::new();
+	let node_id = |key_byte: u8| NodeId::from_pubkey(&SecretKey::from_slice(&[key_byte; 32]).unwrap().public_key(&secp_context));
+
+	// restricting the delta to a subgraph filters the counts along with the channels
+	for (key_byte, short_channel_id) in [(1, 1), (4, 2)] {
+		let filters = DeltaFilters { node_ids: [node_id(key_byte)].into_iter().collect(), ..Default::default() };
+		let serialization = serialize_delta_since(network_graph_arc.clone(), 0, filters, logger.clone()).await.unwrap();
+		assert_eq!(serialization.announcement_count, 1);
+		assert_eq!(serialization.update_count, 2);
+		assert_eq!(read_short_channel_ids(&serialization.data).unwrap(), vec![short_channel_id]);
+	}
+
+	let filters = DeltaFilters { node_ids: [node_id(5)].into_iter().collect(), ..Default::default() };
+	let serialization = serialize_delta_since(network_graph_arc.clone(), 0, filters, logger.clone()).await.unwrap();
+	assert_eq!(serialization.announcement_count, 0);
+	assert_eq!(serialization.update_count, 0);
+
+	clean_test_db().await;
+}
+
 #[tokio::test]
 async fn test_full_snapshot_recency_with_wrong_seen_order() {
 	let _sanitizer = SchemaSanitizer::new();
@@ -362,7 +1056,7 @@ async fn test_full_snapshot_recency_with_wrong_seen_order() {
 	let client_graph_arc = Arc::new(client_graph);
 
 	{ // sync after initial seed
-		let serialization = serialize_delta(network_graph_arc.clone(), 0, logger.clone()).await;
+		let serialization = serialize_delta(network_graph_arc.clone(), 0, &SerializationConfig::from_config(), logger.clone()).await.unwrap();
 		logger.assert_log_contains("rapid_gossip_sync_server", "announcement channel count: 1", 1);
 
 		let channel_count = network_graph_arc.read_only().channels().len();
@@ -437,7 +1131,7 @@ async fn test_full_snapshot_recency_with_wrong_propagation_order() {
 	let client_graph_arc = Arc::new(client_graph);
 
 	{ // sync after initial seed
-		let serialization = serialize_delta(network_graph_arc.clone(), 0, logger.clone()).await;
+		let serialization = serialize_delta(network_graph_arc.clone(), 0, &SerializationConfig::from_config(), logger.clone()).await.unwrap();
 		logger.assert_log_contains("rapid_gossip_sync_server", "announcement channel count: 1", 1);
 
 		let channel_count = network_graph_arc.read_only().channels().len();
@@ -566,7 +1260,7 @@ async fn test_full_snapshot_mutiny_scenario() {
 	let client_graph_arc = Arc::new(client_graph);
 
 	{ // sync after initial seed
-		let serialization = serialize_delta(network_graph_arc.clone(), 0, logger.clone()).await;
+		let serialization = serialize_delta(network_graph_arc.clone(), 0, &SerializationConfig::from_config(), logger.clone()).await.unwrap();
 		logger.assert_log_contains("rapid_gossip_sync_server", "announcement channel count: 1", 1);
 
 		let channel_count = network_graph_arc.read_only().channels().len();
@@ -675,7 +1369,7 @@ async fn test_full_snapshot_interlaced_channel_timestamps() {
 	let client_graph_arc = Arc::new(client_graph);
 
 	{ // sync after initial seed
-		let serialization = serialize_delta(network_graph_arc.clone(), 0, logger.clone()).await;
+		let serialization = serialize_delta(network_graph_arc.clone(), 0, &SerializationConfig::from_config(), logger.clone()).await.unwrap();
 		logger.assert_log_contains("rapid_gossip_sync_server", "announcement channel count: 2", 1);
 
 		let channel_count = network_graph_arc.read_only().channels().len();
@@ -706,48 +1400,68 @@ async fn test_full_snapshot_interlaced_channel_timestamps() {
 	clean_test_db().await;
 }
 
+/// A snapshotter generating 5-second and full snapshots into `cache_path`, with at most ten
+/// symlinks 20 seconds apart, uploading to `uploader`
+fn persistence_test_snapshotter(network_graph_arc: Arc<NetworkGraph<Arc<TestLogger>>>, logger: Arc<TestLogger>, cache_path: &str, event_sender: broadcast::Sender<SnapshotEvent>, uploader: Arc<RecordingUploader>) -> Snapshotter<Arc<TestLogger>> {
+	let snapshotter_config = SnapshotterConfig {
+		cache_path: cache_path.to_string(),
+		serialization: SerializationConfig { snapshot_interval: 5, ..SerializationConfig::from_config() },
+		snapshot_scopes: vec![5, u64::MAX],
+		max_symlink_count: Some(10),
+		symlink_granularity_interval: 20,
+		..SnapshotterConfig::from_config()
+	};
+	let dependencies = SnapshotterDependencies { config: snapshotter_config, uploader: UploadCredentials::Fixed(uploader, "api key".to_string()), ..SnapshotterDependencies::from_config() };
+	Snapshotter::with_dependencies(network_graph_arc, logger, event_sender, dependencies)
+}
+
+/// Seed the db with a channel whose `false` direction was updated a second before its `true`
+/// direction was at `timestamp`
+async fn seed_persistence_test_channel(network_graph_arc: &Arc<NetworkGraph<Arc<TestLogger>>>, logger: &Arc<TestLogger>, short_channel_id: u64, timestamp: u32) {
+	let (mut persister, receiver) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+	let announcement = generate_announcement(short_channel_id);
+	network_graph_arc.update_channel_from_announcement_no_lookup(&announcement).unwrap();
+	receiver.send(GossipMessage::ChannelAnnouncement(announcement, None)).await.unwrap();
+
+	{ // direction true
+		let update = generate_update(short_channel_id, true, timestamp, 0, 0, 0, 0, 10);
+		network_graph_arc.update_channel_unsigned(&update.contents).unwrap();
+		receiver.send(GossipMessage::ChannelUpdate(update, None)).await.unwrap();
+	}
+
+	{ // direction false
+		let update = generate_update(short_channel_id, false, timestamp - 1, 0, 0, 0, 0, 38);
+		network_graph_arc.update_channel_unsigned(&update.contents).unwrap();
+		receiver.send(GossipMessage::ChannelUpdate(update, None)).await.unwrap();
+	}
+
+	drop(receiver);
+	persister.persist_gossip().await;
+}
+
 #[tokio::test]
 async fn test_full_snapshot_persistence() {
 	let schema_sanitizer = SchemaSanitizer::new();
 	let logger = Arc::new(TestLogger::new());
 	let network_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
 	let network_graph_arc = Arc::new(network_graph);
-	let snapshotter = Snapshotter::new(network_graph_arc.clone(), logger.clone());
 	let cache_sanitizer = CacheSanitizer::new(&schema_sanitizer);
+	let cache_path = cache_sanitizer.cache_path();
+	let (event_sender, _) = broadcast::channel(1);
+	let snapshotter = persistence_test_snapshotter(network_graph_arc.clone(), logger.clone(), &cache_path, event_sender, Arc::new(RecordingUploader::default()));
 
 	let short_channel_id = 1;
 	let timestamp = current_time();
 	println!("timestamp: {}", timestamp);
 
-	{ // seed the db
-		let (mut persister, receiver) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
-		let announcement = generate_announcement(short_channel_id);
-		network_graph_arc.update_channel_from_announcement_no_lookup(&announcement).unwrap();
-		receiver.send(GossipMessage::ChannelAnnouncement(announcement, None)).await.unwrap();
-
-		{ // direction true
-			let update = generate_update(short_channel_id, true, timestamp, 0, 0, 0, 0, 10);
-			network_graph_arc.update_channel_unsigned(&update.contents).unwrap();
-			receiver.send(GossipMessage::ChannelUpdate(update, None)).await.unwrap();
-		}
-
-		{ // direction false
-			let update = generate_update(short_channel_id, false, timestamp - 1, 0, 0, 0, 0, 38);
-			network_graph_arc.update_channel_unsigned(&update.contents).unwrap();
-			receiver.send(GossipMessage::ChannelUpdate(update, None)).await.unwrap();
-		}
-
-
-		drop(receiver);
-		persister.persist_gossip().await;
-	}
+	// seed the db
+	seed_persistence_test_channel(&network_graph_arc, &logger, short_channel_id, timestamp).await;
 
-	let cache_path = cache_sanitizer.cache_path();
 	let symlink_path = format!("{}/symlinks/0.bin", cache_path);
 
 	// generate snapshots
 	{
-		snapshotter.generate_snapshots(20, 5, &[5, u64::MAX], &cache_path, Some(10)).await;
+		snapshotter.generate_snapshots().await;
 
 		let symlinked_data = fs::read(&symlink_path).unwrap();
 		let client_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
@@ -756,7 +1470,7 @@ async fn test_full_snapshot_persistence() {
 		let rgs = RapidGossipSync::new(client_graph_arc.clone(), logger.clone());
 		let update_result = rgs.update_network_graph(&symlinked_data).unwrap();
 		// the update result must be a multiple of our snapshot granularity
-		assert_eq!(update_result % config::snapshot_generation_interval(), 0);
+		assert_eq!(update_result % 5, 0);
 
 		let readonly_graph = client_graph_arc.read_only();
 		let channels = readonly_graph.channels();
@@ -785,7 +1499,7 @@ async fn test_full_snapshot_persistence() {
 
 	// regenerate snapshots
 	{
-		snapshotter.generate_snapshots(20, 5, &[5, u64::MAX], &cache_path, Some(10)).await;
+		snapshotter.generate_snapshots().await;
 
 		let symlinked_data = fs::read(&symlink_path).unwrap();
 		let client_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
@@ -794,7 +1508,7 @@ async fn test_full_snapshot_persistence() {
 		let rgs = RapidGossipSync::new(client_graph_arc.clone(), logger.clone());
 		let update_result = rgs.update_network_graph(&symlinked_data).unwrap();
 		// the update result must be a multiple of our snapshot granularity
-		assert_eq!(update_result % config::snapshot_generation_interval(), 0);
+		assert_eq!(update_result % 5, 0);
 
 		let readonly_graph = client_graph_arc.read_only();
 		let channels = readonly_graph.channels();
@@ -811,3 +1525,147 @@ async fn test_full_snapshot_persistence() {
 	// clean up afterwards
 	clean_test_db().await;
 }
+
+#[tokio::test]
+async fn test_snapshot_event() {
+	let schema_sanitizer = SchemaSanitizer::new();
+	let logger = Arc::new(TestLogger::new());
+	let network_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
+	let network_graph_arc = Arc::new(network_graph);
+	let cache_sanitizer = CacheSanitizer::new(&schema_sanitizer);
+	let cache_path = cache_sanitizer.cache_path();
+	let (event_sender, mut event_receiver) = broadcast::channel(1);
+	let uploader = Arc::new(RecordingUploader::default());
+	let snapshotter = persistence_test_snapshotter(network_graph_arc.clone(), logger.clone(), &cache_path, event_sender, uploader.clone());
+
+	let timestamp = current_time();
+	seed_persistence_test_channel(&network_graph_arc, &logger, 1, timestamp).await;
+
+	// left behind by something other than the cycles, but still taking up space
+	fs::create_dir_all(format!("{}/archive", cache_path)).unwrap();
+	fs::write(format!("{}/archive/leftover", cache_path), b"stale").unwrap();
+
+	snapshotter.generate_snapshots().await;
+
+	let event = event_receiver.try_recv().unwrap();
+	assert_eq!(event.output_dir, cache_path);
+	assert_eq!(event.scopes.len(), 2);
+	assert_eq!(event.scopes[1].scope, u64::MAX);
+	assert_eq!(event.scopes[1].announcement_count, 1);
+	assert_eq!(event.newest_update_timestamp, Some(timestamp));
+	// the reference timestamp is the capture time rounded down to the 5-second interval
+	assert!(event.capture_delay < Duration::from_secs(5));
+	assert!(!event.exceeded_capture_delay_threshold);
+
+	// everything below the cache path is counted, not only the finalized set
+	let cache_entries = fs::read_dir(&cache_path).unwrap().count() as u64;
+	let snapshot_entries = fs::read_dir(format!("{}/snapshots", cache_path)).unwrap().count() as u64;
+	let symlink_entries = fs::read_dir(format!("{}/symlinks", cache_path)).unwrap().count() as u64;
+	assert_eq!(event.inode_count, cache_entries + snapshot_entries + symlink_entries + 1);
+	let snapshot_bytes: u64 = fs::read_dir(format!("{}/snapshots", cache_path)).unwrap().map(|entry| entry.unwrap().metadata().unwrap().len()).sum();
+	assert!(event.disk_usage_bytes >= snapshot_bytes + b"stale".len() as u64);
+
+	// only the full snapshot is uploaded
+	assert_eq!(*uploader.uploads.lock().unwrap(), vec![(0, event.scopes[1].message_count)]);
+
+	clean_test_db().await;
+}
+
+#[tokio::test]
+async fn test_upload_from_file_encoding() {
+	let schema_sanitizer = SchemaSanitizer::new();
+	let logger = Arc::new(TestLogger::new());
+	let network_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
+	let network_graph_arc = Arc::new(network_graph);
+	let cache_sanitizer = CacheSanitizer::new(&schema_sanitizer);
+	let cache_path = cache_sanitizer.cache_path();
+	let (event_sender, _) = broadcast::channel(1);
+	let uploader = Arc::new(RecordingUploader::default());
+	// the configured encoding isn't one of the written variants
+	let snapshotter_config = SnapshotterConfig {
+		cache_path: cache_path.clone(),
+		snapshot_compressions: vec![],
+		upload_content_encoding: "gzip",
+		upload_from_file: true,
+		..SnapshotterConfig::from_config()
+	};
+	let dependencies = SnapshotterDependencies { config: snapshotter_config, snapshot_socket_path: None, uploader: UploadCredentials::Fixed(uploader.clone(), "api key".to_string()), ..SnapshotterDependencies::from_config() };
+	let snapshotter = Snapshotter::with_dependencies(network_graph_arc.clone(), logger.clone(), event_sender, dependencies);
+
+	seed_persistence_test_channel(&network_graph_arc, &logger, 1, current_time()).await;
+	snapshotter.generate_snapshots().await;
+
+	// the uncompressed file is labeled as such
+	assert_eq!(*uploader.file_content_encodings.lock().unwrap(), vec!["identity".to_string()]);
+
+	clean_test_db().await;
+}
+
+#[tokio::test]
+async fn test_rebuild_symlinks() {
+	let schema_sanitizer = SchemaSanitizer::new();
+	let logger = Arc::new(TestLogger::new());
+	let network_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
+	let network_graph_arc = Arc::new(network_graph);
+	let cache_sanitizer = CacheSanitizer::new(&schema_sanitizer);
+	let cache_path = cache_sanitizer.cache_path();
+	let (event_sender, _) = broadcast::channel(1);
+	let snapshotter = persistence_test_snapshotter(network_graph_arc.clone(), logger.clone(), &cache_path, event_sender, Arc::new(RecordingUploader::default()));
+
+	seed_persistence_test_channel(&network_graph_arc, &logger, 1, current_time()).await;
+	snapshotter.generate_snapshots().await;
+
+	let read_symlinks = || {
+		let mut symlinks: Vec<_> = fs::read_dir(format!("{}/symlinks", cache_path)).unwrap().flatten()
+			.filter_map(|entry| fs::read_link(entry.path()).ok().map(|target| (entry.file_name(), target)))
+			.collect();
+		symlinks.sort_unstable();
+		symlinks
+	};
+	let symlink_targets = read_symlinks();
+	fs::remove_dir_all(format!("{}/symlinks", cache_path)).unwrap();
+	// the dummy's symlink isn't counted
+	let snapshotter_config = SnapshotterConfig {
+		cache_path: cache_path.clone(),
+		max_symlink_count: Some(10),
+		symlink_granularity_interval: 20,
+		..SnapshotterConfig::from_config()
+	};
+	assert_eq!(rebuild_symlinks(&snapshotter_config, &logger), Ok(10));
+	let rebuilt_symlink_targets = read_symlinks();
+	assert_eq!(symlink_targets.len(), 11);
+	assert_eq!(rebuilt_symlink_targets, symlink_targets);
+	assert_eq!(verify_served_tree(&cache_path, 20, &[5, u64::MAX], u64::MAX, true, Duration::from_secs(60)), vec![]);
+
+	clean_test_db().await;
+}
+
+#[tokio::test]
+async fn test_verify_served_tree() {
+	let schema_sanitizer = SchemaSanitizer::new();
+	let logger = Arc::new(TestLogger::new());
+	let network_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
+	let network_graph_arc = Arc::new(network_graph);
+	let cache_sanitizer = CacheSanitizer::new(&schema_sanitizer);
+	let cache_path = cache_sanitizer.cache_path();
+	let (event_sender, _) = broadcast::channel(1);
+	let snapshotter = persistence_test_snapshotter(network_graph_arc.clone(), logger.clone(), &cache_path, event_sender, Arc::new(RecordingUploader::default()));
+
+	seed_persistence_test_channel(&network_graph_arc, &logger, 1, current_time()).await;
+	snapshotter.generate_snapshots().await;
+
+	let max_update_age = Duration::from_secs(60);
+	assert_eq!(verify_served_tree(&cache_path, 20, &[5, u64::MAX], u64::MAX, true, max_update_age), vec![]);
+
+	// break the tree
+	fs::remove_file(format!("{}/symlinks/update_time.txt", cache_path)).unwrap();
+	let symlink_path = format!("{}/symlinks/0.bin", cache_path);
+	let full_snapshot = fs::read_link(&symlink_path).unwrap();
+	fs::remove_file(format!("{}/symlinks/{}", cache_path, full_snapshot.to_string_lossy())).unwrap();
+
+	let problems = verify_served_tree(&cache_path, 20, &[5, u64::MAX], u64::MAX, true, max_update_age);
+	assert!(problems.contains(&TreeProblem::MissingUpdateTime));
+	assert!(problems.iter().any(|problem| matches!(problem, TreeProblem::DanglingSymlink { symlink, .. } if symlink.ends_with("/0.bin"))));
+
+	clean_test_db().await;
+}